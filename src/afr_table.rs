@@ -0,0 +1,149 @@
+use std::{
+    fs,
+    io,
+    path::Path,
+};
+use crate::table::{cell_value, detect_separator, invalid, parse_cell};
+
+/// Loads above this are implausible, so an axis reaching past it is RPM.
+const MAX_LOAD: f32 = 20.0;
+
+/// A commanded AFR table indexed by engine load and RPM, as found in the ECU calibration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AfrTable {
+    pub load_axis: Vec<f32>,
+    pub rpm_axis: Vec<f32>,
+    /// One row per load, one column per RPM.
+    pub values: Vec<Vec<f32>>,
+}
+
+impl AfrTable {
+    /// Loads a table from a file, see `parse` for the accepted layout.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&bytes))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses a 2-D table as copied from tuning software.
+    ///
+    /// The first numeric row is the column axis and the first cell of every following row is
+    /// the row axis; the corner cell may be blank or a label. Either axis may be the load axis:
+    /// the one with values above any plausible load is taken to be RPM.
+    /// Separators follow `CalibrationTable::parse`.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let separator = detect_separator(text);
+        let decimal_comma = separator == ';';
+
+        let mut rows: Vec<Vec<f32>> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let cells: Vec<&str> = line.split(separator).map(str::trim).collect();
+            let parsed: Vec<Option<f32>> = cells.iter().map(|cell| parse_cell(cell, decimal_comma)).collect();
+            if parsed.iter().all(Option::is_none) {
+                continue;
+            }
+            // The corner cell of the axis row is blank or a label
+            let skip = usize::from(rows.is_empty() && parsed[0].is_none());
+            let row = parsed.iter().zip(&cells).enumerate().skip(skip)
+                .filter(|(_, (_, cell))| !cell.is_empty())
+                .map(|(column, (&value, cell))| cell_value(value, cell, number, column))
+                .collect::<io::Result<Vec<f32>>>()?;
+            rows.push(row);
+        }
+
+        let Some((columns, body)) = rows.split_first() else {
+            return Err(invalid("Table has no cells".to_string()));
+        };
+        let mut row_axis = Vec::with_capacity(body.len());
+        let mut values = Vec::with_capacity(body.len());
+        for row in body {
+            if row.len() != columns.len() + 1 {
+                return Err(invalid(format!(
+                    "Expected an axis cell and {} values per row, found {} cells",
+                    columns.len(), row.len()
+                )));
+            }
+            row_axis.push(row[0]);
+            values.push(row[1..].to_vec());
+        }
+
+        let row_is_rpm = row_axis.iter().any(|&x| x > MAX_LOAD) && columns.iter().all(|&x| x <= MAX_LOAD);
+        let table = if row_is_rpm {
+            let transposed = (0..columns.len()).map(|j| values.iter().map(|row| row[j]).collect()).collect();
+            AfrTable { load_axis: columns.clone(), rpm_axis: row_axis, values: transposed }
+        } else {
+            AfrTable { load_axis: row_axis, rpm_axis: columns.clone(), values }
+        };
+        table.validate()?;
+        Ok(table)
+    }
+
+    fn validate(&self) -> io::Result<()> {
+        for (name, axis) in [("Load", &self.load_axis), ("RPM", &self.rpm_axis)] {
+            if axis.is_empty() {
+                return Err(invalid(format!("{} axis has no cells", name)));
+            }
+            if axis.windows(2).any(|pair| pair[0].partial_cmp(&pair[1]) != Some(std::cmp::Ordering::Less)) {
+                return Err(invalid(format!("{} axis is not strictly increasing", name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the commanded AFR at `load` and `rpm` by bilinear interpolation.
+    /// Points outside the table are clamped to its edges.
+    pub fn lookup(&self, load: f32, rpm: f32) -> f32 {
+        let (i, ti) = locate(&self.load_axis, load);
+        let (j, tj) = locate(&self.rpm_axis, rpm);
+        let i1 = (i + 1).min(self.load_axis.len() - 1);
+        let j1 = (j + 1).min(self.rpm_axis.len() - 1);
+        let top = lerp(self.values[i][j], self.values[i][j1], tj);
+        let bottom = lerp(self.values[i1][j], self.values[i1][j1], tj);
+        lerp(top, bottom, ti)
+    }
+}
+
+/// Finds the axis cell at or below `x` and the fraction of the way to the next cell.
+fn locate(axis: &[f32], x: f32) -> (usize, f32) {
+    let last = axis.len() - 1;
+    if last == 0 || x <= axis[0] {
+        return (0, 0.0);
+    }
+    if x >= axis[last] {
+        return (last, 0.0);
+    }
+    let i = axis.partition_point(|&a| a <= x) - 1;
+    (i, (x - axis[i]) / (axis[i + 1] - axis[i]))
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bilinear_lookup() {
+        let table = AfrTable::parse("Load\\RPM\t2000\t4000\n0.5\t14.7\t14.7\n1.5\t12.0\t11.0\n").unwrap();
+        assert_eq!(table.rpm_axis, vec![2000.0, 4000.0]);
+        assert_eq!(table.lookup(0.5, 2000.0), 14.7);
+        assert!((table.lookup(1.0, 3000.0) - (14.7 + 14.7 + 12.0 + 11.0) / 4.0).abs() < 1e-5);
+        // Clamped outside the axes
+        assert_eq!(table.lookup(3.0, 8000.0), 11.0);
+        assert_eq!(table.lookup(0.0, 500.0), 14.7);
+    }
+
+    #[test]
+    fn test_rpm_rows_are_transposed() {
+        let table = AfrTable::parse(",0.5,1.5\n2000,14.7,12.0\n4000,14.7,11.0\n").unwrap();
+        assert_eq!(table.load_axis, vec![0.5, 1.5]);
+        assert_eq!(table.rpm_axis, vec![2000.0, 4000.0]);
+        assert_eq!(table.lookup(1.5, 4000.0), 11.0);
+        assert!(AfrTable::parse(",0.5,1.5\n2000,14.7\n").is_err());
+        let error = AfrTable::parse(",0.5,1.5\n2000,14.7,-inf\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 2, column 3: '-inf' is not a finite number");
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    fs,
+    io,
+    path::Path,
+};
+use crate::{data::MafSignal, table::CalibrationTable};
+
+/// Finest resolution looked for in a table, in decimals.
+const MAX_DECIMALS: i32 = 6;
+
+/// Formats `table` the way Accesstuner Race copies and pastes a 1-D table:
+/// a tab-separated row with the signal axis, then a row with the airflow values
+/// rounded to `resolution`. Lines end in CRLF, as on the Windows clipboard.
+/// Voltage axes are written exactly; frequency axes in whole Hz.
+pub fn to_atr(table: &CalibrationTable, resolution: f32) -> String {
+    let decimals = decimals(resolution);
+    let axis: Vec<String> = table.axis.iter()
+        .map(|x| match table.signal {
+            MafSignal::Voltage => x.to_string(),
+            MafSignal::Frequency => format!("{:.0}", x),
+        })
+        .collect();
+    let values: Vec<String> = table.values.iter()
+        .map(|&y| format!("{:.*}", decimals, round_to(y, resolution)))
+        .collect();
+    format!("{}\r\n{}\r\n", axis.join("\t"), values.join("\t"))
+}
+
+/// Writes `table` to `path` in the Accesstuner Race layout, see `to_atr`.
+pub fn write_atr<P: AsRef<Path>>(path: P, table: &CalibrationTable, resolution: f32) -> io::Result<()> {
+    fs::write(path, to_atr(table, resolution))
+}
+
+/// Returns the resolution of the airflow values of `table`: the coarsest of 1, 0.1, 0.01, ...
+/// that every value is a multiple of, as far as an `f32` can tell. A stock table exported as
+/// `6.13850021` has the resolution of the `6.1385` it holds, not of the decimals it was printed with.
+pub fn resolution(table: &CalibrationTable) -> f32 {
+    (0..=MAX_DECIMALS)
+        .map(|decimals| 10f32.powi(-decimals))
+        .find(|&step| table.values.iter().all(|&value| round_to(value, step) == value))
+        .unwrap_or(10f32.powi(-MAX_DECIMALS))
+}
+
+/// Rounds `value` to the nearest multiple of `resolution`.
+/// The result is the `f32` nearest to the printed multiple, so it survives a print/parse round trip.
+pub fn round_to(value: f32, resolution: f32) -> f32 {
+    let steps = (value as f64 / resolution as f64).round();
+    format!("{:.*}", decimals(resolution), steps * resolution as f64).parse().unwrap_or(value)
+}
+
+/// Number of decimals needed to print multiples of `resolution`.
+fn decimals(resolution: f32) -> usize {
+    (-resolution.log10()).ceil().max(0.0) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolution_from_values() {
+        assert_eq!(resolution(&CalibrationTable::load("./data/stock1.csv").unwrap()), 1e-4);
+        let table = |values: Vec<f32>| CalibrationTable::new(vec![1.0, 2.0, 3.0], values).unwrap();
+        assert_eq!(resolution(&table(vec![2.0, 4.25, 8.5])), 0.01);
+        assert_eq!(resolution(&table(vec![2.0, 4.0, 8.0])), 1.0);
+        assert_eq!(resolution(&table(vec![2.0, 1.0 / 3.0, 8.0])), 1e-6);
+    }
+
+    #[test]
+    fn test_atr_round_trip() {
+        let stock = CalibrationTable::load("./data/stock1.csv").unwrap();
+        let resolution = resolution(&stock);
+        let exported = to_atr(&stock, resolution);
+        let parsed = CalibrationTable::parse(&exported).unwrap();
+
+        assert_eq!(parsed.axis, stock.axis);
+        assert_eq!(parsed.values, stock.values);
+        // Exporting the parsed table again must not change it
+        assert_eq!(to_atr(&parsed, resolution), exported);
+    }
+
+    #[test]
+    fn test_rounds_to_resolution() {
+        let stock = CalibrationTable::new(vec![1500.0, 3000.0, 6000.0], vec![2.0, 8.5, 40.25]).unwrap();
+        let table = CalibrationTable::new(stock.axis.clone(), vec![2.004, 8.5, 40.126]).unwrap();
+        let parsed = CalibrationTable::parse(&to_atr(&table, resolution(&stock))).unwrap();
+        assert_eq!(parsed.signal, MafSignal::Frequency);
+        assert_eq!(parsed.axis, table.axis);
+        assert_eq!(parsed.values, vec![2.0, 8.5, 40.13]);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::data::MafSignal;
+
+/// Lowest MAF voltage covered by the default bins.
+pub const MAF_MIN_V: f32 = 0.0;
+/// Highest MAF voltage covered by the default bins.
+pub const MAF_MAX_V: f32 = 5.0;
+/// Width of one default bin, matching the 128-cell stock scaling table.
+pub const MAF_BIN_WIDTH: f32 = 5.0 / 128.0;
+/// Highest MAF frequency covered by the frequency bins.
+pub const MAF_MAX_HZ: f32 = 15000.0;
+/// Width of one frequency bin.
+pub const MAF_BIN_WIDTH_HZ: f32 = 125.0;
+
+/// Fixed-width bins along the MAF signal axis.
+/// Used to summarise how samples are spread over the sensor range.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bins {
+    pub min: f32,
+    pub width: f32,
+    pub count: usize,
+}
+
+impl Bins {
+    /// Creates bins of `width` covering `min..=max`.
+    pub fn new(min: f32, max: f32, width: f32) -> Self {
+        let count = ((max - min) / width).ceil().max(1.0) as usize;
+        Bins { min, width, count }
+    }
+
+    /// Returns the index of the bin containing `x`, or `None` if `x` is outside the range.
+    pub fn index(&self, x: f32) -> Option<usize> {
+        if !x.is_finite() || x < self.min {
+            return None;
+        }
+        let i = ((x - self.min) / self.width) as usize;
+        // The upper edge belongs to the last bin
+        if i == self.count && x <= self.min + self.width * self.count as f32 {
+            return Some(i - 1);
+        }
+        (i < self.count).then_some(i)
+    }
+
+    /// Returns the lower and upper edge of bin `i`.
+    pub fn range(&self, i: usize) -> (f32, f32) {
+        let lo = self.min + self.width * i as f32;
+        (lo, lo + self.width)
+    }
+
+    /// Bins spanning the range of a MAF signal: 0-5 V at stock table resolution, or 0-15 kHz.
+    pub fn for_signal(signal: MafSignal) -> Self {
+        match signal {
+            MafSignal::Voltage => Bins::new(MAF_MIN_V, MAF_MAX_V, MAF_BIN_WIDTH),
+            MafSignal::Frequency => Bins::new(0.0, MAF_MAX_HZ, MAF_BIN_WIDTH_HZ),
+        }
+    }
+}
+
+/// Running statistics for the samples that fell into one bin.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+/// `hits` counts the samples whatever their weight; `sum` and `weight` are weighted.
+pub struct BinStats {
+    pub hits: usize,
+    pub sum: f64,
+    pub weight: f64,
+}
+
+impl BinStats {
+    /// Adds a sample value to the bin.
+    pub fn add(&mut self, value: f32) {
+        self.add_weighted(value, 1.0);
+    }
+
+    /// Adds a sample value to the bin, counting `weight` times towards the mean.
+    pub fn add_weighted(&mut self, value: f32, weight: f32) {
+        self.hits += 1;
+        self.sum += value as f64 * weight as f64;
+        self.weight += weight as f64;
+    }
+
+    /// Returns the weighted mean of the values added, or `None` for an empty bin.
+    pub fn mean(&self) -> Option<f32> {
+        (self.hits > 0 && self.weight > 0.0).then(|| (self.sum / self.weight) as f32)
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    fs,
+    io,
+    path::Path,
+};
+use crate::{
+    data::LogData,
+    table::invalid,
+};
+
+/// A stretch of one log to leave out, e.g. a known bad part of a drive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeRange {
+    /// Path or file name of the log, matched against the end of the log path.
+    pub log: String,
+    pub from: f32,
+    pub to: f32,
+}
+
+impl TimeRange {
+    /// Returns `true` if the range applies to the log at `path`.
+    pub fn applies_to(&self, path: &Path) -> bool {
+        path.ends_with(&self.log)
+    }
+}
+
+/// Columns, time ranges and MAF signal ranges to exclude, see `rules/blacklist.txt`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Blacklist {
+    /// Log columns whose header contains any of these are not parsed.
+    pub columns: Vec<String>,
+    pub times: Vec<TimeRange>,
+    /// MAF signal ranges left out of the fit.
+    pub signals: Vec<(f32, f32)>,
+}
+
+impl Blacklist {
+    /// Loads a blacklist file, see `parse` for the format.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .and_then(|text| Self::parse(&text))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses one entry per line: `column NAME`, `time LOG FROM TO` or `signal FROM TO`.
+    /// Blank lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut blacklist = Blacklist::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| invalid(format!("Line {}: {}", number + 1, message));
+            let (kind, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let rest = rest.trim();
+            match kind {
+                "column" if !rest.is_empty() => blacklist.columns.push(rest.to_string()),
+                "time" => {
+                    let (log, from, to) = rest.rsplitn(3, char::is_whitespace)
+                        .collect::<Vec<&str>>()
+                        .try_into()
+                        .map(|[to, from, log]: [&str; 3]| (log.trim(), from, to))
+                        .map_err(|_| error("expected 'time LOG FROM TO'"))?;
+                    let (from, to) = range(from, to).ok_or_else(|| error("invalid time range"))?;
+                    blacklist.times.push(TimeRange { log: log.to_string(), from, to });
+                }
+                "signal" => {
+                    let (from, to) = rest.split_once(char::is_whitespace)
+                        .and_then(|(from, to)| range(from, to.trim()))
+                        .ok_or_else(|| error("expected 'signal FROM TO'"))?;
+                    blacklist.signals.push((from, to));
+                }
+                _ => return Err(error(&format!("unknown entry '{}'", line))),
+            }
+        }
+        Ok(blacklist)
+    }
+
+    /// Returns `true` if a log column with this header must not be parsed.
+    pub fn excludes_column(&self, header: &str) -> bool {
+        self.columns.iter().any(|column| header.contains(column.as_str()))
+    }
+
+    /// Returns `true` if the MAF signal `x` lies in an excluded range.
+    pub fn excludes_signal(&self, x: f32) -> bool {
+        self.signals.iter().any(|&(from, to)| x >= from && x <= to)
+    }
+
+    /// Drops the rows of the log at `path` that fall in one of its excluded time ranges.
+    /// Returns the number of rows dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a time range applies to the log but it has no time column.
+    pub fn drop_times(&self, path: &Path, data: &mut LogData) -> io::Result<usize> {
+        let ranges: Vec<&TimeRange> = self.times.iter().filter(|range| range.applies_to(path)).collect();
+        if ranges.is_empty() {
+            return Ok(0);
+        }
+        if data.time().is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Blacklisted time ranges need a Time column"));
+        }
+        let mut keep = vec![true; data.len()];
+        for range in ranges {
+            keep[data.rows_between(range.from, range.to)].fill(false);
+        }
+        data.retain_rows(&keep);
+        Ok(keep.iter().filter(|&&keep| !keep).count())
+    }
+}
+
+/// Parses a `FROM TO` pair, which must be in increasing order.
+fn range(from: &str, to: &str) -> Option<(f32, f32)> {
+    match (from.parse::<f32>(), to.parse::<f32>()) {
+        (Ok(from), Ok(to)) if from <= to => Some((from, to)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::LogField,
+        testing::{log, row},
+    };
+
+    const BLACKLIST: &str = "# bad drive\ncolumn Boost Air Temp.\ntime my logs/log1.csv 1.0 2.0\nsignal 0 1.5\n";
+
+    #[test]
+    fn test_parse_and_exclude() {
+        let blacklist = Blacklist::parse(BLACKLIST).unwrap();
+        assert!(blacklist.excludes_column("Boost Air Temp. (F)"));
+        assert!(!blacklist.excludes_column("Intake Temp. (F)"));
+        assert_eq!(blacklist.times, vec![TimeRange { log: "my logs/log1.csv".to_string(), from: 1.0, to: 2.0 }]);
+        assert!(blacklist.excludes_signal(1.5));
+        assert!(!blacklist.excludes_signal(1.6));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_entries() {
+        assert!(Blacklist::parse("signal 2 1").is_err());
+        assert!(Blacklist::parse("voltage 0 1").is_err());
+    }
+
+    #[test]
+    fn test_drop_times() {
+        let blacklist = Blacklist::parse(BLACKLIST).unwrap();
+        let mut data = log((0..4).map(|t| row(2.0).with(LogField::TIME, t as f32)));
+        assert_eq!(blacklist.drop_times(Path::new("log1.csv"), &mut data).unwrap(), 0);
+        assert_eq!(blacklist.drop_times(Path::new("/data/my logs/log1.csv"), &mut data).unwrap(), 2);
+        assert_eq!(data.get(&LogField::TIME).unwrap(), &vec![0.0, 3.0]);
+    }
+}
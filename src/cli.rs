@@ -0,0 +1,419 @@
+use std::path::PathBuf;
+use crate::{
+    afr_table::AfrTable,
+    blacklist::Blacklist,
+    correction::{CorrectionConfig, OpenLoop},
+    delay::Delay,
+    logger::{LoggerConfig, DEFAULT_BAUD},
+    resample::{Resampling, Weighting},
+    rules::RuleSet,
+    smooth::{Kernel, Smoothing},
+    trim_map::MapAxis,
+};
+
+/// Usage text printed when the command line cannot be parsed.
+pub const USAGE: &str = "\
+Usage:
+  maf_cal [fit] [--stock STOCK] [--plot] [--map-by rpm|load] [LOG...]
+                                         Fit the given logs (default ./data/log1.csv),
+                                         optionally plotting the result in the terminal;
+                                         trims are also mapped by MAF signal and RPM (or load)
+  maf_cal plot [PRE POST]                Plot pre-correction.csv and post-correction.csv in the terminal
+  maf_cal edit STOCK PROPOSED [LOG...] [--out OUT]
+                                         Review and hand-edit a proposed table, showing the hits per
+                                         cell from the logs, and save it to OUT (default maf-table.txt)
+  maf_cal init PROJECT STOCK             Start a project file from a stock MAF table
+  maf_cal next PROJECT [--from K] LOG... Generate the next revision from logs driven on revision K
+                                         (default latest), starting from revision K's table
+  maf_cal history PROJECT                Show how trims converged across revisions
+  maf_cal export PROJECT OUT [--rev K]   Write revision K (default latest) in Accesstuner Race layout
+  maf_cal log ADAPTER OUT --maf-voltage REQUEST [--baud BAUD] [--rows N] [--display]
+                                         Log live from an ELM327 at tcp:HOST:PORT or a serial device,
+                                         reading the MAF voltage with REQUEST (e.g. a mode 22 request),
+                                         optionally showing samples and trims per MAF bin
+  maf_cal watch LOG                      Follow a log another program is writing and show its running
+                                         per-bin trims and corrected airflow;
+                                         --delay, --resample and --weight need a complete log
+  maf_cal simulate LOG tcp:ADDRESS|pty [--speed X]
+                                         Replay a log as an ELM327 to test the logger without a car
+
+Correction options for fit, next, watch and edit:
+  --open-loop SIGNAL   Correct rows at or above this MAF signal from the wideband instead of trims
+  --afr-table TABLE    Commanded AFR table by load and RPM, used if the log has no commanded AFR
+  --target-afr AFR     AFR (or lambda) the ECU commands in open loop, used if nothing else applies
+  --delay DELAY        Shift trims and AFR back by SECONDS, by RPM:SECONDS,... points, or 'auto'
+  --rules RULES        Drop rows matching the rules in this file instead of the default rules
+  --no-rules           Keep every row
+  --blacklist FILE     Exclude the columns, time ranges and MAF ranges listed in FILE
+  --resample HZ[:mean|:linear]
+                       Bring every log to HZ rows per second before merging, averaging the rows in
+                       each interval (default) or interpolating between them
+  --weight rows|time   Let each log count by its rows (default) or by the time it covers
+
+Smoothing options for fit (with --stock) and next:
+  --smooth KERNEL      Set each cell to the mean corrected airflow of its samples instead of the fitted curve, then
+                       smooth those cells with ma:WIDTH, gaussian:SIGMA, sg:WIDTH:ORDER or spline:LAMBDA,
+                       widths in cells; cells without samples are left alone
+  --smooth-range FROM:TO
+                       Only smooth cells in this MAF signal range
+  --lock FROM:TO       Keep the cells in this MAF signal range as they are; may be repeated";
+
+/// A parsed command line.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Fit {
+        stock: Option<PathBuf>,
+        logs: Vec<PathBuf>,
+        correction: CorrectionConfig,
+        plot: bool,
+        smoothing: Option<Smoothing>,
+        map_by: MapAxis,
+    },
+    Init { project: PathBuf, stock: PathBuf },
+    Next { project: PathBuf, from: Option<usize>, logs: Vec<PathBuf>, correction: CorrectionConfig, smoothing: Option<Smoothing> },
+    History { project: PathBuf },
+    Export { project: PathBuf, out: PathBuf, rev: Option<usize> },
+    Log { out: PathBuf, config: LoggerConfig },
+    Simulate { log: PathBuf, port: String, speed: f32 },
+    Watch { log: PathBuf, correction: CorrectionConfig },
+    Plot { pre: PathBuf, post: PathBuf },
+    Edit { stock: PathBuf, proposed: PathBuf, logs: Vec<PathBuf>, out: PathBuf, correction: CorrectionConfig },
+}
+
+impl Command {
+    /// Parses the arguments following the program name.
+    /// Arguments that do not start with a known command are treated as logs to fit.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self, String> {
+        let mut args = args.into_iter().peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("fit" | "init" | "next" | "history" | "export" | "log" | "simulate" | "watch" | "plot" | "edit") => args.next().unwrap(),
+            _ => "fit".to_string(),
+        };
+
+        match command.as_str() {
+            "init" => {
+                let project = args.next().ok_or("init: missing PROJECT")?.into();
+                let stock = args.next().ok_or("init: missing STOCK")?.into();
+                Ok(Command::Init { project, stock })
+            }
+            "next" => {
+                let project = args.next().ok_or("next: missing PROJECT")?.into();
+                let mut from = None;
+                let mut logs = Vec::new();
+                let mut correction = CorrectionFlags::default();
+                let mut smoothing = SmoothingFlags::default();
+                while let Some(arg) = args.next() {
+                    if arg == "--from" {
+                        from = Some(revision(args.next(), "next: --from")?);
+                    } else if !correction.parse(&arg, &mut args)? && !smoothing.parse(&arg, &mut args)? {
+                        logs.push(arg.into());
+                    }
+                }
+                if logs.is_empty() {
+                    return Err("next: at least one LOG is required".to_string());
+                }
+                Ok(Command::Next { project, from, logs, correction: correction.finish()?, smoothing: smoothing.finish()? })
+            }
+            "history" => {
+                let project = args.next().ok_or("history: missing PROJECT")?.into();
+                Ok(Command::History { project })
+            }
+            "export" => {
+                let project = args.next().ok_or("export: missing PROJECT")?.into();
+                let out = args.next().ok_or("export: missing OUT")?.into();
+                let rev = match args.next().as_deref() {
+                    Some("--rev") => Some(revision(args.next(), "export: --rev")?),
+                    Some(other) => return Err(format!("export: unexpected argument '{}'", other)),
+                    None => None,
+                };
+                Ok(Command::Export { project, out, rev })
+            }
+            "log" => {
+                let adapter = args.next().ok_or("log: missing ADAPTER")?;
+                let out = args.next().ok_or("log: missing OUT")?.into();
+                let mut maf_voltage = None;
+                let mut config = LoggerConfig { adapter, baud: DEFAULT_BAUD, maf_voltage: String::new(), rows: None, display: false };
+                while let Some(arg) = args.next() {
+                    match arg.as_str() {
+                        "--maf-voltage" => maf_voltage = Some(args.next().ok_or("log: --maf-voltage needs a request")?),
+                        "--baud" => config.baud = number(args.next(), "log: --baud")?,
+                        "--rows" => config.rows = Some(number(args.next(), "log: --rows")?),
+                        "--display" => config.display = true,
+                        other => return Err(format!("log: unexpected argument '{}'", other)),
+                    }
+                }
+                // The fit needs the MAF Voltage column, which no standard PID fills
+                config.maf_voltage = maf_voltage.ok_or("log: --maf-voltage is required, the fit needs the MAF voltage")?;
+                Ok(Command::Log { out, config })
+            }
+            "watch" => {
+                let mut log = None;
+                let mut correction = CorrectionFlags::default();
+                while let Some(arg) = args.next() {
+                    if !correction.parse(&arg, &mut args)? {
+                        if log.is_some() {
+                            return Err(format!("watch: unexpected argument '{}'", arg));
+                        }
+                        log = Some(arg.into());
+                    }
+                }
+                let log = log.ok_or("watch: missing LOG")?;
+                let correction = correction.finish()?;
+                if correction.delay.is_some() || correction.resample.is_some() || correction.weighting != Weighting::Rows {
+                    return Err("watch: --delay, --resample and --weight need a complete log".to_string());
+                }
+                Ok(Command::Watch { log, correction })
+            }
+            "plot" => {
+                let (pre, post) = match (args.next(), args.next(), args.next()) {
+                    (None, _, _) => ("pre-correction.csv".into(), "post-correction.csv".into()),
+                    (Some(pre), Some(post), None) => (pre.into(), post.into()),
+                    (Some(_), None, _) => return Err("plot: POST is required with PRE".to_string()),
+                    (_, _, Some(other)) => return Err(format!("plot: unexpected argument '{}'", other)),
+                };
+                Ok(Command::Plot { pre, post })
+            }
+            "edit" => {
+                let stock = args.next().ok_or("edit: missing STOCK")?.into();
+                let proposed = args.next().ok_or("edit: missing PROPOSED")?.into();
+                let mut out = PathBuf::from("maf-table.txt");
+                let mut logs = Vec::new();
+                let mut correction = CorrectionFlags::default();
+                while let Some(arg) = args.next() {
+                    if arg == "--out" {
+                        out = args.next().ok_or("edit: --out needs a path")?.into();
+                    } else if !correction.parse(&arg, &mut args)? {
+                        logs.push(arg.into());
+                    }
+                }
+                Ok(Command::Edit { stock, proposed, logs, out, correction: correction.finish()? })
+            }
+            "simulate" => {
+                let log = args.next().ok_or("simulate: missing LOG")?.into();
+                let port = args.next().ok_or("simulate: missing tcp:ADDRESS or pty")?;
+                if port != "pty" && !port.starts_with("tcp:") {
+                    return Err(format!("simulate: expected tcp:ADDRESS or pty, found '{}'", port));
+                }
+                let speed = match args.next().as_deref() {
+                    Some("--speed") => number(args.next(), "simulate: --speed")?,
+                    Some(other) => return Err(format!("simulate: unexpected argument '{}'", other)),
+                    None => 1.0,
+                };
+                Ok(Command::Simulate { log, port, speed })
+            }
+            _ => {
+                let mut stock = None;
+                let mut plot = false;
+                let mut map_by = MapAxis::default();
+                let mut logs = Vec::new();
+                let mut correction = CorrectionFlags::default();
+                let mut smoothing = SmoothingFlags::default();
+                while let Some(arg) = args.next() {
+                    if arg == "--stock" {
+                        stock = Some(args.next().ok_or("fit: --stock needs a table path")?.into());
+                    } else if arg == "--plot" {
+                        plot = true;
+                    } else if arg == "--map-by" {
+                        let value = args.next().ok_or("fit: --map-by needs rpm or load")?;
+                        map_by = MapAxis::parse(&value).map_err(|e| format!("fit: --map-by: {}", e))?;
+                    } else if !correction.parse(&arg, &mut args)? && !smoothing.parse(&arg, &mut args)? {
+                        logs.push(arg.into());
+                    }
+                }
+                let smoothing = smoothing.finish()?;
+                if smoothing.is_some() && stock.is_none() {
+                    return Err("fit: --smooth needs --stock".to_string());
+                }
+                Ok(Command::Fit { stock, logs, correction: correction.finish()?, plot, smoothing, map_by })
+            }
+        }
+    }
+}
+
+/// Parses the value following a revision flag.
+fn revision(value: Option<String>, flag: &str) -> Result<usize, String> {
+    let value = value.ok_or_else(|| format!("{} needs a revision number", flag))?;
+    value.parse().map_err(|_| format!("{}: invalid revision '{}'", flag, value))
+}
+
+/// Parses the numeric value following a flag.
+fn number<T: std::str::FromStr>(value: Option<String>, flag: &str) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("{}: invalid number '{}'", flag, value))
+}
+
+/// Correction flags collected while parsing `fit` or `next`.
+#[derive(Default)]
+struct CorrectionFlags {
+    open_loop: Option<f32>,
+    target_afr: Option<f32>,
+    afr_table: Option<String>,
+    delay: Option<Delay>,
+    rules: Option<String>,
+    no_rules: bool,
+    blacklist: Option<String>,
+    resample: Option<Resampling>,
+    weighting: Weighting,
+}
+
+impl CorrectionFlags {
+    /// Consumes `arg` (and its value) if it is a correction flag. Returns `false` otherwise.
+    fn parse(&mut self, arg: &str, args: &mut impl Iterator<Item = String>) -> Result<bool, String> {
+        if arg == "--afr-table" {
+            self.afr_table = Some(args.next().ok_or("--afr-table needs a table path")?);
+            return Ok(true);
+        }
+        if arg == "--rules" {
+            self.rules = Some(args.next().ok_or("--rules needs a rules file")?);
+            return Ok(true);
+        }
+        if arg == "--blacklist" {
+            self.blacklist = Some(args.next().ok_or("--blacklist needs a file")?);
+            return Ok(true);
+        }
+        if arg == "--no-rules" {
+            self.no_rules = true;
+            return Ok(true);
+        }
+        if arg == "--resample" {
+            let value = args.next().ok_or("--resample needs a rate")?;
+            self.resample = Some(Resampling::parse(&value).map_err(|e| format!("--resample: {}", e))?);
+            return Ok(true);
+        }
+        if arg == "--weight" {
+            let value = args.next().ok_or("--weight needs rows or time")?;
+            self.weighting = Weighting::parse(&value).map_err(|e| format!("--weight: {}", e))?;
+            return Ok(true);
+        }
+        if arg == "--delay" {
+            let value = args.next().ok_or("--delay needs a value")?;
+            self.delay = Some(Delay::parse(&value).map_err(|e| format!("--delay: {}", e))?);
+            return Ok(true);
+        }
+        let slot = match arg {
+            "--open-loop" => &mut self.open_loop,
+            "--target-afr" => &mut self.target_afr,
+            _ => return Ok(false),
+        };
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        *slot = Some(value.parse().map_err(|_| format!("{}: invalid number '{}'", arg, value))?);
+        Ok(true)
+    }
+
+    /// Builds the correction settings, loading the AFR table, rules and blacklist files if given.
+    fn finish(self) -> Result<CorrectionConfig, String> {
+        let rules = match (self.rules, self.no_rules) {
+            (Some(_), true) => return Err("--rules and --no-rules cannot be combined".to_string()),
+            (Some(path), false) => RuleSet::load(&path).map_err(|e| e.to_string())?,
+            (None, true) => RuleSet::default(),
+            (None, false) => RuleSet::default_rules(),
+        };
+        let blacklist = self.blacklist
+            .map(|path| Blacklist::load(&path).map_err(|e| e.to_string()))
+            .transpose()?
+            .unwrap_or_default();
+        let Some(boundary) = self.open_loop else {
+            if self.target_afr.is_some() || self.afr_table.is_some() {
+                return Err("--target-afr and --afr-table need --open-loop".to_string());
+            }
+            return Ok(CorrectionConfig {
+                open_loop: None,
+                delay: self.delay,
+                rules,
+                blacklist,
+                resample: self.resample,
+                weighting: self.weighting,
+            });
+        };
+        let afr_table = self.afr_table
+            .map(|path| AfrTable::load(&path).map_err(|e| e.to_string()))
+            .transpose()?;
+        Ok(CorrectionConfig {
+            open_loop: Some(OpenLoop { boundary, target_afr: self.target_afr, afr_table }),
+            delay: self.delay,
+            rules,
+            blacklist,
+            resample: self.resample,
+            weighting: self.weighting,
+        })
+    }
+}
+
+/// Smoothing flags collected while parsing `fit` or `next`.
+#[derive(Default)]
+struct SmoothingFlags {
+    kernel: Option<Kernel>,
+    range: Option<(f32, f32)>,
+    locks: Vec<(f32, f32)>,
+}
+
+impl SmoothingFlags {
+    /// Consumes `arg` (and its value) if it is a smoothing flag. Returns `false` otherwise.
+    fn parse(&mut self, arg: &str, args: &mut impl Iterator<Item = String>) -> Result<bool, String> {
+        match arg {
+            "--smooth" => {
+                let value = args.next().ok_or("--smooth needs a kernel")?;
+                self.kernel = Some(Kernel::parse(&value).map_err(|e| format!("--smooth: {}", e))?);
+            }
+            "--smooth-range" => self.range = Some(signal_range(arg, args.next())?),
+            "--lock" => self.locks.push(signal_range(arg, args.next())?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Builds the smoothing settings, if a kernel was given.
+    fn finish(self) -> Result<Option<Smoothing>, String> {
+        match (self.kernel, self.range) {
+            (Some(kernel), range) => Ok(Some(Smoothing { kernel, range, locks: self.locks })),
+            (None, Some(_)) => Err("--smooth-range needs --smooth".to_string()),
+            (None, None) if !self.locks.is_empty() => Err("--lock needs --smooth".to_string()),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Parses the `FROM:TO` MAF signal range given to `flag`.
+fn signal_range(flag: &str, value: Option<String>) -> Result<(f32, f32), String> {
+    let value = value.ok_or_else(|| format!("{} needs FROM:TO", flag))?;
+    value.split_once(':')
+        .and_then(|(from, to)| Some((from.parse::<f32>().ok()?, to.parse::<f32>().ok()?)))
+        .filter(|(from, to)| from < to)
+        .ok_or_else(|| format!("{}: expected FROM:TO, found '{}'", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        Command::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_log_needs_maf_voltage() {
+        assert!(parse("log tcp:127.0.0.1:35000 out.csv --rows 10").unwrap_err().contains("--maf-voltage"));
+        let Ok(Command::Log { config, .. }) = parse("log tcp:127.0.0.1:35000 out.csv --maf-voltage 221001") else {
+            panic!("log with --maf-voltage should parse");
+        };
+        assert_eq!(config.maf_voltage, "221001");
+    }
+
+    #[test]
+    fn test_lock_ranges() {
+        let Ok(Command::Next { smoothing: Some(smoothing), .. }) = parse("next p.json log.csv --smooth ma:3 --lock 0:1.2 --lock 4:5") else {
+            panic!("next with --lock should parse");
+        };
+        assert_eq!(smoothing.locks, vec![(0.0, 1.2), (4.0, 5.0)]);
+        assert!(parse("next p.json log.csv --lock 0:1.2").unwrap_err().contains("--smooth"));
+        assert!(parse("next p.json log.csv --smooth ma:3 --lock 2:1").is_err());
+    }
+
+    #[test]
+    fn test_watch_rejects_whole_log_options() {
+        for option in ["--delay 0.2", "--resample 10", "--weight time"] {
+            assert!(parse(&format!("watch log.csv {}", option)).unwrap_err().contains("complete log"), "{}", option);
+        }
+        assert!(parse("watch log.csv --weight rows --no-rules").is_ok());
+    }
+}
@@ -0,0 +1,239 @@
+use std::io;
+use crate::{
+    afr_table::AfrTable,
+    blacklist::Blacklist,
+    data::{LogData, LogField},
+    delay::Delay,
+    resample::{Resampling, Weighting},
+    rules::RuleSet,
+    session::Sample,
+};
+
+/// Stoichiometric AFR of gasoline, used to convert lambda readings to AFR.
+pub const STOICH_AFR: f32 = 14.7;
+/// Wideband readings below this are lambda rather than AFR.
+const LAMBDA_LIMIT: f32 = 3.0;
+
+/// How a sample's airflow correction was derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Closed loop: combined STFT + LTFT.
+    Trims,
+    /// Open loop: measured AFR against the commanded or target AFR.
+    Wideband,
+}
+
+/// Open-loop correction settings.
+/// Rows with a MAF signal at or above `boundary` are corrected from the wideband.
+/// The commanded AFR of a row comes from the first of: the log's commanded AFR column,
+/// `afr_table` interpolated at the row's load and RPM, or the fixed `target_afr`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenLoop {
+    pub boundary: f32,
+    pub target_afr: Option<f32>,
+    pub afr_table: Option<AfrTable>,
+}
+
+/// Settings deciding how every row of a log is turned into a corrected sample.
+/// With a `delay`, the trim and AFR channels are first aligned to the MAF signal, see `delay::align`.
+/// With `resample`, every log is then brought onto the same time base, see `resample::resample`.
+/// Rows matching any of the `rules` are then dropped, as are the columns, time ranges and
+/// MAF signal ranges in the `blacklist`. The `weighting` decides how much each log counts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CorrectionConfig {
+    pub open_loop: Option<OpenLoop>,
+    pub delay: Option<Delay>,
+    pub rules: RuleSet,
+    pub blacklist: Blacklist,
+    pub resample: Option<Resampling>,
+    pub weighting: Weighting,
+}
+
+/// Corrects the measured airflow of every row in `data` and pairs it with the MAF signal.
+///
+/// Closed-loop rows scale airflow by the combined fuel trim, a percentage. Open-loop rows scale it by
+/// measured / commanded AFR: a leaner than commanded mixture means more air than the MAF reported.
+/// Rows without a usable trim, or open-loop rows without a usable wideband or target reading, are dropped.
+/// Samples carry the intake temperature, or the boost air temperature if that is all the log has.
+///
+/// # Errors
+///
+/// Returns an error if open-loop correction is enabled but the log has no wideband column,
+/// or has no source of commanded AFR: no commanded AFR column, no load and RPM columns for
+/// the AFR table, and no target AFR.
+pub fn correct(data: &LogData, source: usize, config: &CorrectionConfig) -> io::Result<Vec<Sample>> {
+    let (Some(mafv), Some(mass), Some(stft), Some(ltft)) = (
+        data.get(&LogField::MAFV),
+        data.get(&LogField::MASS),
+        data.get(&LogField::STFT),
+        data.get(&LogField::LTFT),
+    ) else {
+        return Ok(Vec::new());
+    };
+
+    let wideband = match &config.open_loop {
+        Some(open_loop) => {
+            let afr = data.get(&LogField::AFR).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Open-loop correction needs an Actual AFR column")
+            })?;
+            let commanded = Commanded {
+                column: data.get(&LogField::CAFR),
+                table: match (&open_loop.afr_table, data.get(&LogField::LOAD), data.get(&LogField::RPM)) {
+                    (Some(table), Some(load), Some(rpm)) => Some(TableLookup { table, load, rpm }),
+                    _ => None,
+                },
+                fixed: open_loop.target_afr,
+            };
+            if commanded.column.is_none() && commanded.table.is_none() && commanded.fixed.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Open-loop correction needs a commanded AFR column, an AFR table with load and RPM columns, or a target AFR",
+                ));
+            }
+            Some((open_loop.boundary, afr, commanded))
+        }
+        None => None,
+    };
+
+    // The MAF sits upstream of any intercooler, so prefer the intake temperature
+    let iat = data.get(&LogField::IAT).or_else(|| data.get(&LogField::BAT));
+    let rpm = data.get(&LogField::RPM);
+    let load = data.get(&LogField::LOAD);
+
+    let mut samples = Vec::with_capacity(mafv.len());
+    for (i, (&x, &maf)) in mafv.iter().zip(mass).enumerate() {
+        let (trim, method) = match &wideband {
+            Some((boundary, afr, commanded)) if x >= *boundary => {
+                match (to_afr(afr[i]), commanded.at(i).and_then(to_afr)) {
+                    (Some(measured), Some(target)) => ((measured / target - 1.0) * 100.0, Method::Wideband),
+                    _ => continue,
+                }
+            }
+            _ => match stft[i] + ltft[i] {
+                trim if trim.is_finite() => (trim, Method::Trims),
+                _ => continue,
+            },
+        };
+        let column = |values: Option<&Vec<f32>>| values.map_or(f32::NAN, |values| values[i]);
+        samples.push(Sample {
+            x,
+            y: maf * (1.0 + trim / 100.0),
+            trim,
+            method,
+            source,
+            iat: column(iat),
+            rpm: column(rpm),
+            load: column(load),
+            weight: 1.0,
+        });
+    }
+    Ok(samples)
+}
+
+/// The sources of commanded AFR available for one log, in order of preference.
+struct Commanded<'a> {
+    column: Option<&'a Vec<f32>>,
+    table: Option<TableLookup<'a>>,
+    fixed: Option<f32>,
+}
+
+/// An AFR table with the load and RPM columns to index it by.
+struct TableLookup<'a> {
+    table: &'a AfrTable,
+    load: &'a [f32],
+    rpm: &'a [f32],
+}
+
+impl Commanded<'_> {
+    /// Returns the commanded AFR of row `i`, if any source has a usable value for it.
+    fn at(&self, i: usize) -> Option<f32> {
+        let from_table = || {
+            self.table.as_ref()
+                .filter(|lookup| lookup.load[i].is_finite() && lookup.rpm[i].is_finite())
+                .map(|lookup| lookup.table.lookup(lookup.load[i], lookup.rpm[i]))
+        };
+        self.column
+            .map(|column| column[i])
+            .filter(|value| value.is_finite())
+            .or_else(from_table)
+            .or(self.fixed)
+    }
+}
+
+/// Converts a wideband reading to AFR, accepting lambda as well. Returns `None` for unusable readings.
+fn to_afr(reading: f32) -> Option<f32> {
+    if !reading.is_finite() || reading <= 0.0 {
+        None
+    } else if reading < LAMBDA_LIMIT {
+        Some(reading * STOICH_AFR)
+    } else {
+        Some(reading)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{log, row};
+
+    fn open_loop(target_afr: Option<f32>, afr_table: Option<AfrTable>) -> CorrectionConfig {
+        CorrectionConfig { open_loop: Some(OpenLoop { boundary: 3.5, target_afr, afr_table }), ..CorrectionConfig::default() }
+    }
+
+    /// Closed loop at 2 V with +5% trims, open loop at 4 V running 5% lean of an 11.0 target,
+    /// and open loop at 4.1 V on target, read as lambda.
+    fn mixed() -> LogData {
+        log([
+            row(2.0).with(LogField::STFT, 3.0).with(LogField::LTFT, 2.0).with(LogField::AFR, 14.7),
+            row(4.0).with(LogField::MASS, 200.0).with(LogField::LTFT, 2.0).with(LogField::AFR, 11.55),
+            row(4.1).with(LogField::MASS, 210.0).with(LogField::LTFT, 2.0).with(LogField::AFR, 11.0 / STOICH_AFR),
+        ])
+    }
+
+    #[test]
+    fn test_closed_loop_rows_use_trims() {
+        let samples = correct(&mixed(), 0, &open_loop(Some(11.0), None)).unwrap();
+        assert_eq!(samples[0].method, Method::Trims);
+        assert!((samples[0].y - 21.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_trims_scale_airflow_by_percent() {
+        // -10% at 200 g/s is 20 g/s less air, not 10
+        let data = log([row(4.0).with(LogField::MASS, 200.0).with(LogField::STFT, -6.0).with(LogField::LTFT, -4.0)]);
+        let samples = correct(&data, 0, &CorrectionConfig::default()).unwrap();
+        assert!((samples[0].y - 180.0).abs() < 1e-3);
+        assert_eq!(samples[0].trim, -10.0);
+    }
+
+    #[test]
+    fn test_open_loop_rows_use_wideband() {
+        let samples = correct(&mixed(), 0, &open_loop(Some(11.0), None)).unwrap();
+        assert_eq!(samples[1].method, Method::Wideband);
+        assert!((samples[1].y - 210.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_lambda_reading() {
+        let samples = correct(&mixed(), 0, &open_loop(Some(11.0), None)).unwrap();
+        assert!((samples[2].y - 210.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_open_loop_needs_a_target() {
+        assert!(correct(&mixed(), 0, &open_loop(None, None)).is_err());
+    }
+
+    #[test]
+    fn test_afr_table_before_fixed_target() {
+        // The AFR table takes precedence over the fixed target where load and RPM are logged
+        let data = log([row(4.0)
+            .with(LogField::MASS, 200.0)
+            .with(LogField::AFR, 12.1)
+            .with(LogField::LOAD, 1.0)
+            .with(LogField::RPM, 3000.0)]);
+        let afr_table = AfrTable::parse(",2000,4000\n0.5,11.0,11.0\n1.5,11.0,11.0\n").unwrap();
+        let samples = correct(&data, 0, &open_loop(Some(12.1), Some(afr_table))).unwrap();
+        assert!((samples[0].y - 220.0).abs() < 1e-3);
+    }
+}
@@ -2,7 +2,7 @@ use std::{
     fs::File,
     io,
 };
-use csv::Writer;
+use csv::{ReaderBuilder, Writer};
 
 /// Writes the provided x and y data arrays to a CSV file with the given filename.
 ///
@@ -35,3 +35,27 @@ pub fn write_to_csv(filename: &str, x_data: &[f32], y_data: &[f32]) -> io::Resul
     wtr.flush()?;
     Ok(())
 }
+
+/// Reads x and y data arrays back from a CSV file written by `write_to_csv`.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or a record is not a pair of numbers.
+pub fn read_from_csv(filename: &str) -> io::Result<(Vec<f32>, Vec<f32>)> {
+    let mut reader = ReaderBuilder::new().has_headers(false).from_reader(File::open(filename)?);
+    let mut x_data = Vec::new();
+    let mut y_data = Vec::new();
+    for (number, record) in reader.records().enumerate() {
+        let record = record?;
+        let value = |i: usize| record.get(i).and_then(|v| v.trim().parse::<f32>().ok());
+        let (Some(x_val), Some(y_val)) = (value(0), value(1)) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: line {} is not an x, y pair", filename, number + 1),
+            ));
+        };
+        x_data.push(x_val);
+        y_data.push(y_val);
+    }
+    Ok((x_data, y_data))
+}
@@ -1,7 +1,11 @@
 use std::{
+    fmt,
     hash::{Hash, Hasher},
-    collections::{HashSet, HashMap},
+    collections::HashMap,
+    ops::Range,
 };
+use serde::{Deserialize, Serialize};
+use crate::bins::{BinStats, MAF_MAX_V};
 
 /// A wrapper around the `f32` type to ensure consistent hashing and equality checks for floating point numbers.
 /// This is useful to handle floating point comparisons and to use floats as keys in collections.
@@ -12,16 +16,17 @@ impl Eq for F32 {}
 
 impl Hash for F32 {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        let bits = unsafe { std::mem::transmute::<f32, u32>(self.0) };
-        bits.hash(state);
+        self.0.to_bits().hash(state);
     }
 }
 
 /// A macro that provides a mechanism to define an enum and its associated methods.
 /// It auto-generates methods to convert enum variants to strings (headers),
-/// to convert strings back to enum variants, and to list all enum variants.
+/// to match log headers against them, and to list all enum variants.
+/// Each variant has a canonical header, optionally followed by `| "alias"` alternatives.
 macro_rules! define_enum_and_variants {
-    ($name:ident { $($variant:ident => $str:expr),* }) => {
+    ($name:ident { $($variant:ident => $str:literal $(| $alias:literal)*),* }) => {
+        #[allow(clippy::upper_case_acronyms)]
         #[derive(Debug, Clone, Copy, Eq, Hash, PartialEq)]
         pub enum $name {
             $($variant),*
@@ -29,21 +34,27 @@ macro_rules! define_enum_and_variants {
 
         impl $name {
             /// Converts an enum variant into its corresponding header string.
-            pub fn to_header(&self) -> &'static str {
+            pub fn to_header(self) -> &'static str {
                 match self {
                     $(Self::$variant => $str),*
                 }
             }
 
-            /// Converts a header string into its corresponding enum variant.
-            /// Returns `None` if the string doesn't match any variant.
-            pub fn from_header(header: &str) -> Option<Self> {
-                match header {
-                    $($str => Some(Self::$variant),)*
-                    _ => None,
+            /// Lists the canonical header and every alias of a variant.
+            pub fn aliases(self) -> &'static [&'static str] {
+                match self {
+                    $(Self::$variant => &[$str $(, $alias)*]),*
                 }
             }
 
+            /// Returns `true` if a log column header names the canonical header or any alias,
+            /// ignoring case, its unit suffix and a trailing period: `Intake Temp. (F)` is the
+            /// intake temperature, but `Injector Time (ms)` is not the time.
+            pub fn matches_header(self, header: &str) -> bool {
+                let name = split_header(header).0.trim_end_matches('.');
+                self.aliases().iter().any(|alias| alias.eq_ignore_ascii_case(name))
+            }
+
             /// Lists all the enum variants.
             pub fn variants() -> &'static [Self] {
                 &[$(Self::$variant),*]
@@ -53,49 +64,431 @@ macro_rules! define_enum_and_variants {
 }
 
 // Utilizing the macro to define the `LogField` enum.
+// `MAFV` is the MAF signal, which is a frequency rather than a voltage on some platforms.
 define_enum_and_variants!(LogField {
-    MAFV => "MAF Voltage",
+    MAFV => "MAF Voltage" | "MAF Freq" | "MAF Hz",
     MASS => "Mass Airflow",
     STFT => "Short Term FT",
-    LTFT => "Long Term FT"
+    LTFT => "Long Term FT",
+    AFR => "Actual AFR" | "Wideband AFR" | "Measured AFR",
+    CAFR => "Commanded AFR" | "Target AFR" | "AFR Target" | "Desired AFR",
+    LOAD => "Calculated Load" | "Engine Load",
+    RPM => "RPM" | "Engine Speed",
+    TIME => "Time",
+    IAT => "Intake Temp" | "Intake Air Temp" | "IAT",
+    BAT => "Boost Air Temp" | "Charge Air Temp",
+    KNOCK => "Knock Retard",
+    IDC => "Inj. Duty Cycle" | "Injector Duty",
+    TPS => "Throttle Position"
 });
 
+impl LogField {
+    /// Returns `true` for fields every log must have. Optional fields read as NaN where missing.
+    pub fn is_required(self) -> bool {
+        matches!(self, LogField::MAFV | LogField::MASS | LogField::STFT | LogField::LTFT)
+    }
+}
+
+/// The kind of signal a MAF sensor outputs, and so the unit of the MAF axis.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MafSignal {
+    #[default]
+    Voltage,
+    Frequency,
+}
+
+impl MafSignal {
+    /// Returns the unit of the signal.
+    pub fn unit(self) -> &'static str {
+        match self {
+            MafSignal::Voltage => "V",
+            MafSignal::Frequency => "Hz",
+        }
+    }
+
+    /// Guesses the signal from a column header or table label, e.g. `MAF Freq (Hz)`.
+    pub fn from_header(header: &str) -> Option<Self> {
+        let header = header.to_lowercase();
+        if header.contains("hz") || header.contains("freq") {
+            Some(MafSignal::Frequency)
+        } else if header.contains("volt") || header.contains("(v)") {
+            Some(MafSignal::Voltage)
+        } else {
+            None
+        }
+    }
+
+    /// Guesses the signal from the values of an axis: no MAF voltage exceeds the sensor supply.
+    pub fn from_axis(axis: &[f32]) -> Self {
+        if axis.iter().any(|&x| x > MAF_MAX_V + 0.5) {
+            MafSignal::Frequency
+        } else {
+            MafSignal::Voltage
+        }
+    }
+}
+
+/// Logger metadata, as written by an AccessPort in its `AP Info:[...]` field.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub device: String,
+    pub firmware: Option<String>,
+    pub platform: Option<String>,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.device)?;
+        if let Some(firmware) = &self.firmware {
+            write!(f, ", firmware {}", firmware)?;
+        }
+        if let Some(platform) = &self.platform {
+            write!(f, ", platform {}", platform)?;
+        }
+        Ok(())
+    }
+}
+
+/// One column of a log: its name and unit as the header gives them, and a value per row.
+/// Values are NaN where the log had nothing parsable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    pub unit: Option<String>,
+    pub values: Vec<f32>,
+}
+
+impl Channel {
+    /// Creates an empty channel from a `Name (unit)` or bare `Name` header.
+    pub fn from_header(header: &str) -> Self {
+        let (name, unit) = split_header(header);
+        Channel { name: name.to_string(), unit: unit.map(str::to_string), values: Vec::new() }
+    }
+
+    /// Returns the header the channel was read from, or would be written with.
+    pub fn header(&self) -> String {
+        match &self.unit {
+            Some(unit) => format!("{} ({})", self.name, unit),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Splits a `Name (unit)` header into its trimmed name and unit; a bare `Name` has no unit.
+fn split_header(header: &str) -> (&str, Option<&str>) {
+    let header = header.trim();
+    match header.strip_suffix(')').and_then(|rest| rest.rsplit_once('(')) {
+        Some((name, unit)) if !name.trim().is_empty() => (name.trim(), Some(unit.trim())),
+        _ => (header, None),
+    }
+}
+
 /// Represents the structured format for logging data with dynamic fields.
-/// Uses a `HashMap` where the key is a `LogField` enum variant and the value is a vector of `f32` data points.
+/// Every loaded column is a `Channel`, found by name with `channel`; the `LogField` variants
+/// are aliases for the well-known channels, found with `get`.
+/// Required fields are always present; optional fields only once added with `add_field`
+/// or `add_channel`. `device` describes the logger, if the log says.
+///
+/// The `LogField::TIME` channel, if present, indexes the rows: logs read from disk keep it
+/// monotonic, so rows can be found by time, resampled and differentiated.
+#[derive(Debug, Clone)]
 pub struct LogData {
-    data: HashMap<LogField, Vec<f32>>,
+    channels: Vec<Channel>,
+    fields: HashMap<LogField, usize>,
+    pub signal: MafSignal,
+    pub device: Option<DeviceInfo>,
 }
 
 impl LogData {
-    /// Inserts a new data value into the appropriate vector based on the provided `LogField`.
-    /// If the `LogField` is `MAFV`, ensures the value is unique before inserting.
-    pub fn push(&mut self, field: LogField, value: f32, seen: &mut HashSet<F32>) {
-        if let Some(vec) = self.data.get_mut(&field) {
-            if let LogField::MAFV = field {
-                if !seen.contains(&F32(value)) {
-                    seen.insert(F32(value));
-                } else {
-                    return;
-                }
-            }
-            vec.push(value);
+    /// Returns a log with no channels at all, not even the required ones.
+    pub fn empty() -> Self {
+        LogData { channels: Vec::new(), fields: HashMap::new(), signal: MafSignal::default(), device: None }
+    }
+
+    /// Inserts a new data value into the channel the `LogField` refers to.
+    pub fn push(&mut self, field: LogField, value: f32) {
+        if let Some(&channel) = self.fields.get(&field) {
+            self.channels[channel].values.push(value);
         }
     }
 
+    /// Adds an empty channel for an optional field, so rows can carry it.
+    pub fn add_field(&mut self, field: LogField) {
+        if !self.fields.contains_key(&field) {
+            self.add_channel(Channel::from_header(field.to_header()), Some(field));
+        }
+    }
+
+    /// Adds a channel and returns its index. `field` makes it the channel of that well-known
+    /// field, unless another channel already is.
+    pub fn add_channel(&mut self, channel: Channel, field: Option<LogField>) -> usize {
+        let index = self.channels.len();
+        self.channels.push(channel);
+        if let Some(field) = field {
+            self.fields.entry(field).or_insert(index);
+        }
+        index
+    }
+
+    /// Inserts one complete row, keeping every field vector aligned to the same sample index.
+    pub fn push_row(&mut self, row: &[(LogField, f32)]) {
+        for &(field, value) in row {
+            self.push(field, value);
+        }
+    }
+
+    /// Inserts one row holding a value for every channel, in channel order.
+    pub fn push_values(&mut self, values: &[f32]) {
+        debug_assert_eq!(values.len(), self.channels.len());
+        for (channel, &value) in self.channels.iter_mut().zip(values) {
+            channel.values.push(value);
+        }
+    }
+
+    /// Returns the number of rows held, taken from the shortest channel.
+    pub fn len(&self) -> usize {
+        self.channels.iter().map(|channel| channel.values.len()).min().unwrap_or(0)
+    }
+
+    /// Returns `true` if no rows have been stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     /// Retrieves the data vector associated with a given `LogField`.
     pub fn get(&self, field: &LogField) -> Option<&Vec<f32>> {
-        self.data.get(field)
+        self.fields.get(field).map(|&channel| &self.channels[channel].values)
+    }
+
+    /// Lists every channel, in the order of the log's columns.
+    pub fn channels(&self) -> &[Channel] {
+        &self.channels
+    }
+
+    /// Finds a channel by name: first a channel with exactly that name, ignoring case, then the
+    /// well-known field it is an alias of, then a channel whose header contains it.
+    pub fn channel(&self, name: &str) -> Option<&Channel> {
+        let name = name.trim();
+        self.channels.iter().find(|channel| channel.name.eq_ignore_ascii_case(name))
+            .or_else(|| {
+                LogField::variants().iter()
+                    .filter(|field| field.matches_header(name))
+                    .find_map(|field| self.fields.get(field))
+                    .map(|&channel| &self.channels[channel])
+            })
+            .or_else(|| self.channels.iter().find(|channel| channel.header().contains(name)))
+    }
+
+    /// Returns the time of each row in seconds, if the log has a time column.
+    pub fn time(&self) -> Option<&[f32]> {
+        self.get(&LogField::TIME).map(Vec::as_slice)
+    }
+
+    /// Drops the rows whose time is NaN or earlier than the row kept before, so the time column
+    /// never decreases. Returns the number of rows dropped, none without a time column.
+    pub fn make_monotonic(&mut self) -> usize {
+        let Some(time) = self.time() else {
+            return 0;
+        };
+        let mut last = f32::NEG_INFINITY;
+        let keep: Vec<bool> = time.iter()
+            .map(|&t| {
+                let in_order = t >= last;
+                if in_order {
+                    last = t;
+                }
+                in_order
+            })
+            .collect();
+        let dropped = keep.iter().filter(|&&keep| !keep).count();
+        if dropped > 0 {
+            self.retain_rows(&keep);
+        }
+        dropped
+    }
+
+    /// Returns the rows from `from` to `to` seconds, both included.
+    /// The range is empty without a time column.
+    pub fn rows_between(&self, from: f32, to: f32) -> Range<usize> {
+        let Some(time) = self.time() else {
+            return 0..0;
+        };
+        let start = time.partition_point(|&t| t < from);
+        start..time.partition_point(|&t| t <= to).max(start)
+    }
+
+    /// Resamples every channel onto a grid of `rate` rows per second from the first row of the log,
+    /// interpolating linearly between the rows around each instant. A value next to a NaN stays NaN.
+    /// Returns `None` without a time column, rows or a positive rate.
+    pub fn resample(&self, rate: f32) -> Option<LogData> {
+        let time = self.time()?;
+        let (&first, &last) = (time.first()?, time.last()?);
+        if rate.is_nan() || rate <= 0.0 {
+            return None;
+        }
+        let count = ((last - first) as f64 * rate as f64).floor() as usize + 1;
+        let grid: Vec<f32> = (0..count).map(|i| (first as f64 + i as f64 / rate as f64) as f32).collect();
+        let mut resampled = self.clone();
+        for channel in &mut resampled.channels {
+            channel.values = grid.iter().map(|&t| interpolate(time, &channel.values, t)).collect();
+        }
+        Some(resampled)
+    }
+
+    /// Decimates the log to at most `rate` rows per second: the rows in each interval of `1 / rate`
+    /// seconds from the first row become one row holding the mean of their finite values, NaN if
+    /// there are none. Intervals without rows are left out.
+    /// Returns `None` without a time column, rows or a positive rate.
+    pub fn average(&self, rate: f32) -> Option<LogData> {
+        let time = self.time()?;
+        let &first = time.first()?;
+        if rate.is_nan() || rate <= 0.0 {
+            return None;
+        }
+        // Rows are in time order, so each interval is one run of rows
+        let interval = |t: f32| ((t - first) as f64 * rate as f64).floor() as u64;
+        let mut runs: Vec<Range<usize>> = Vec::new();
+        for (row, &t) in time.iter().enumerate() {
+            match runs.last_mut() {
+                Some(run) if interval(time[run.start]) == interval(t) => run.end = row + 1,
+                _ => runs.push(row..row + 1),
+            }
+        }
+        let mut averaged = self.clone();
+        for channel in &mut averaged.channels {
+            channel.values = runs.iter()
+                .map(|run| {
+                    let mut mean = BinStats::default();
+                    channel.values[run.clone()].iter().filter(|v| v.is_finite()).for_each(|&v| mean.add(v));
+                    mean.mean().unwrap_or(f32::NAN)
+                })
+                .collect();
+        }
+        Some(averaged)
+    }
+
+    /// Returns the rate of change per second of the channel `name` (see `channel`) at each row,
+    /// from the rows either side, or the row next to it at either end of the log.
+    /// Rows logged at the same instant as both neighbours have none.
+    /// Returns `None` without a time column or such a channel.
+    pub fn derivative(&self, name: &str) -> Option<Vec<f32>> {
+        let time = self.time()?;
+        let values = &self.channel(name)?.values;
+        let last = values.len().saturating_sub(1);
+        Some((0..values.len())
+            .map(|i| {
+                let (a, b) = (i.saturating_sub(1), (i + 1).min(last));
+                if time[b] > time[a] { (values[b] - values[a]) / (time[b] - time[a]) } else { f32::NAN }
+            })
+            .collect())
+    }
+
+    /// Keeps only the rows whose entry in `keep` is `true`.
+    pub fn retain_rows(&mut self, keep: &[bool]) {
+        for channel in &mut self.channels {
+            let mut row = 0;
+            channel.values.retain(|_| {
+                row += 1;
+                keep.get(row - 1).copied().unwrap_or(true)
+            });
+        }
+    }
+
+    /// Replaces the data vector of a field that is already present. `values` must keep the row count.
+    pub fn replace(&mut self, field: LogField, values: Vec<f32>) {
+        if let Some(&channel) = self.fields.get(&field) {
+            let vec = &mut self.channels[channel].values;
+            debug_assert_eq!(vec.len(), values.len());
+            *vec = values;
+        }
     }
 }
 
 impl Default for LogData {
     /// Provides a default instantiation of `LogData`.
-    /// Initializes empty vectors for each `LogField` variant.
+    /// Initializes an empty channel for each required `LogField` variant.
     fn default() -> Self {
-        let mut data = HashMap::new();
-        for &field in LogField::variants() {
-            data.insert(field, Vec::new());
+        let mut data = LogData::empty();
+        for &field in LogField::variants().iter().filter(|field| field.is_required()) {
+            data.add_field(field);
         }
-        LogData { data }
+        data
+    }
+}
+
+/// Linearly interpolates `values`, sampled at the increasing positions `x`, at `at`:
+/// a time in a log, a signal on a table axis or an RPM between delay points.
+/// Returns NaN outside the sampled range.
+pub fn interpolate(x: &[f32], values: &[f32], at: f32) -> f32 {
+    let Some(&last) = x.last() else {
+        return f32::NAN;
+    };
+    if !(at >= x[0] && at <= last) {
+        return f32::NAN;
+    }
+    let j = x.partition_point(|&p| p <= at);
+    if j == x.len() {
+        return values[j - 1];
+    }
+    let i = j - 1;
+    let span = x[j] - x[i];
+    if span <= 0.0 {
+        return values[i];
+    }
+    values[i] + (values[j] - values[i]) * (at - x[i]) / span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{log, row};
+
+    fn ramp() -> LogData {
+        let rows = [(0.0, 1000.0), (0.1, 1200.0), (0.05, 0.0), (f32::NAN, 0.0), (0.3, 1600.0), (0.2, 0.0), (0.5, f32::NAN)];
+        log(rows.map(|(t, rpm)| row(2.0).with(LogField::TIME, t).with(LogField::RPM, rpm)))
+    }
+
+    /// The ramp with the rows out of time order dropped: 0.0, 0.1, 0.3 and 0.5 s.
+    fn monotonic_ramp() -> LogData {
+        let mut data = ramp();
+        data.make_monotonic();
+        data
+    }
+
+    #[test]
+    fn test_make_monotonic() {
+        let mut data = ramp();
+        assert_eq!(data.make_monotonic(), 3);
+        assert_eq!(data.time().unwrap(), &[0.0, 0.1, 0.3, 0.5]);
+        assert_eq!(data.get(&LogField::RPM).unwrap()[..3], [1000.0, 1200.0, 1600.0]);
+    }
+
+    #[test]
+    fn test_rows_between() {
+        let data = monotonic_ramp();
+        assert_eq!(data.rows_between(0.05, 0.3), 1..3);
+        assert_eq!(data.rows_between(0.4, 0.2), 3..3);
+    }
+
+    #[test]
+    fn test_resample() {
+        // 10 Hz from 0.0 to 0.5 s; the NaN at 0.5 s spreads to the interval before it
+        let resampled = monotonic_ramp().resample(10.0).unwrap();
+        assert_eq!(resampled.len(), 6);
+        let rpm = resampled.get(&LogField::RPM).unwrap();
+        assert!((rpm[2] - 1400.0).abs() < 1e-3);
+        assert!(rpm[4].is_nan());
+        assert!((resampled.time().unwrap()[5] - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_derivative() {
+        let data = monotonic_ramp();
+        let slope = data.derivative("RPM").unwrap();
+        assert!((slope[0] - 2000.0).abs() < 1e-2);
+        assert!((slope[1] - 2000.0).abs() < 1e-2);
+        assert!(slope[3].is_nan());
+        assert!(data.derivative("Boost").is_none());
     }
 }
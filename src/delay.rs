@@ -0,0 +1,190 @@
+use std::io;
+use crate::data::{interpolate, LogData, LogField};
+
+/// Channels that respond to an airflow change only after the transport delay.
+const DELAYED_FIELDS: [LogField; 3] = [LogField::STFT, LogField::LTFT, LogField::AFR];
+/// Longest lag tried by the automatic estimate, in seconds.
+const MAX_AUTO_LAG: f32 = 1.0;
+/// Step between the lags tried by the automatic estimate, in seconds.
+const AUTO_LAG_STEP: f32 = 0.01;
+
+/// How far the trim and AFR channels lag the MAF signal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delay {
+    /// The same lag in seconds for every row.
+    Fixed(f32),
+    /// `(rpm, seconds)` points, interpolated at each row's RPM and clamped at the ends.
+    Rpm(Vec<(f32, f32)>),
+    /// A fixed lag picked by maximizing the cross-correlation of the MAF signal and the response.
+    Auto,
+}
+
+impl Delay {
+    /// Parses `auto`, a number of seconds, or `rpm:seconds` points separated by commas.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if text == "auto" {
+            return Ok(Delay::Auto);
+        }
+        if !text.contains(':') {
+            return text.parse().map(Delay::Fixed).map_err(|_| format!("invalid delay '{}'", text));
+        }
+        let mut points = text.split(',')
+            .map(|point| {
+                let (rpm, seconds) = point.split_once(':').ok_or_else(|| format!("invalid delay point '{}'", point))?;
+                match (rpm.trim().parse(), seconds.trim().parse()) {
+                    (Ok(rpm), Ok(seconds)) => Ok((rpm, seconds)),
+                    _ => Err(format!("invalid delay point '{}'", point)),
+                }
+            })
+            .collect::<Result<Vec<(f32, f32)>, String>>()?;
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Delay::Rpm(points))
+    }
+}
+
+/// Shifts the trim and AFR channels of `data` back in time so each row pairs the MAF signal with
+/// the response it caused. Values between samples are linearly interpolated; rows whose response
+/// falls after the end of the log become NaN. Returns the aligned log and the fixed lag applied,
+/// if the delay is not RPM-dependent.
+///
+/// # Errors
+///
+/// Returns an error if the log has no time column, its time is not monotonic, or an RPM-dependent
+/// delay is requested for a log without an RPM column.
+pub fn align(data: &LogData, delay: &Delay) -> io::Result<(LogData, Option<f32>)> {
+    let time = data.time()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Delay alignment needs a Time column"))?;
+    if time.windows(2).any(|pair| pair[0].partial_cmp(&pair[1]).is_none_or(|order| order.is_gt())) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Time column is not monotonic"));
+    }
+
+    let (lags, fixed): (Vec<f32>, Option<f32>) = match delay {
+        Delay::Fixed(lag) => (vec![*lag; time.len()], Some(*lag)),
+        Delay::Rpm(points) => {
+            let rpm = data.get(&LogField::RPM).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "RPM-dependent delay needs an RPM column")
+            })?;
+            (rpm.iter().map(|&rpm| lag_at_rpm(points, rpm)).collect(), None)
+        }
+        Delay::Auto => {
+            let lag = estimate_lag(data).unwrap_or(0.0);
+            (vec![lag; time.len()], Some(lag))
+        }
+    };
+
+    let mut aligned = data.clone();
+    for field in DELAYED_FIELDS {
+        if let Some(values) = data.get(&field) {
+            let shifted = time.iter().zip(&lags)
+                .map(|(&t, &lag)| interpolate(time, values, t + lag))
+                .collect();
+            aligned.replace(field, shifted);
+        }
+    }
+    Ok((aligned, fixed))
+}
+
+/// Picks the lag in `0..=MAX_AUTO_LAG` at which changes in the MAF signal correlate most strongly
+/// with changes in the response: the wideband if the log has one, otherwise the short term trim.
+/// Returns `None` if the log has no time column or too few rows to correlate.
+pub fn estimate_lag(data: &LogData) -> Option<f32> {
+    let time = data.time()?;
+    let signal = data.get(&LogField::MAFV)?;
+    let response = data.get(&LogField::AFR)
+        .filter(|afr| afr.iter().any(|v| v.is_finite()))
+        .or_else(|| data.get(&LogField::STFT))?;
+
+    let signal_diff: Vec<f32> = signal.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    let mut best: Option<(f32, f32)> = None;
+    let steps = (MAX_AUTO_LAG / AUTO_LAG_STEP).round() as usize;
+    for step in 0..=steps {
+        let lag = step as f32 * AUTO_LAG_STEP;
+        let shifted: Vec<f32> = time.iter().map(|&t| interpolate(time, response, t + lag)).collect();
+        let response_diff: Vec<f32> = shifted.windows(2).map(|pair| pair[1] - pair[0]).collect();
+        if let Some(r) = correlation(&signal_diff, &response_diff) {
+            if best.is_none_or(|(_, best_r)| r.abs() > best_r) {
+                best = Some((lag, r.abs()));
+            }
+        }
+    }
+    best.map(|(lag, _)| lag)
+}
+
+/// Interpolates the RPM-dependent lag table at `rpm`.
+fn lag_at_rpm(points: &[(f32, f32)], rpm: f32) -> f32 {
+    let time: Vec<f32> = points.iter().map(|p| p.0).collect();
+    let lags: Vec<f32> = points.iter().map(|p| p.1).collect();
+    interpolate(&time, &lags, rpm.clamp(time[0], time[time.len() - 1]))
+}
+
+/// Pearson correlation of the pairs where both values are finite.
+fn correlation(a: &[f32], b: &[f32]) -> Option<f32> {
+    let pairs: Vec<(f64, f64)> = a.iter().zip(b)
+        .filter(|(x, y)| x.is_finite() && y.is_finite())
+        .map(|(&x, &y)| (x as f64, y as f64))
+        .collect();
+    if pairs.len() < 3 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let mean_a = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_b = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+    for (x, y) in pairs {
+        cov += (x - mean_a) * (y - mean_b);
+        var_a += (x - mean_a) * (x - mean_a);
+        var_b += (y - mean_b) * (y - mean_b);
+    }
+    (var_a > 0.0 && var_b > 0.0).then(|| (cov / (var_a * var_b).sqrt()) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{log, row};
+
+    fn log_with_lag(lag: f32) -> LogData {
+        // Airflow steps up and down; trims follow `lag` seconds later
+        let step = |t: f32| ((t * 1.3).sin() * 3.0 + (t * 0.7).cos() * 2.0).round();
+        log((0..400).map(|i| {
+            let t = i as f32 * 0.02;
+            row(2.0 + step(t) * 0.1)
+                .with(LogField::TIME, t)
+                .with(LogField::STFT, step(t - lag))
+                .with(LogField::RPM, 3000.0)
+        }))
+    }
+
+    #[test]
+    fn test_auto_estimate_finds_lag() {
+        let lag = estimate_lag(&log_with_lag(0.3)).unwrap();
+        assert!((lag - 0.3).abs() < 0.025, "estimated {}", lag);
+    }
+
+    #[test]
+    fn test_fixed_alignment() {
+        let data = log_with_lag(0.2);
+        let (aligned, fixed) = align(&data, &Delay::Fixed(0.2)).unwrap();
+        assert_eq!(fixed, Some(0.2));
+        let (mafv, stft) = (data.get(&LogField::MAFV).unwrap(), aligned.get(&LogField::STFT).unwrap());
+        for i in 0..380 {
+            assert!((stft[i] - (mafv[i] - 2.0) * 10.0).abs() < 1e-3);
+        }
+        assert!(stft[399].is_nan());
+    }
+
+    #[test]
+    fn test_parse_rpm_delay() {
+        assert_eq!(Delay::parse("6000:0.1, 2000:0.3").unwrap(), Delay::Rpm(vec![(2000.0, 0.3), (6000.0, 0.1)]));
+    }
+
+    #[test]
+    fn test_rpm_alignment() {
+        let data = log_with_lag(0.2);
+        let (by_rpm, fixed) = align(&data, &Delay::Rpm(vec![(2000.0, 0.3), (6000.0, 0.1)])).unwrap();
+        assert_eq!(fixed, None);
+        // 3000 RPM is a quarter of the way from 0.3 s to 0.1 s
+        let expected = interpolate(data.get(&LogField::TIME).unwrap(), data.get(&LogField::STFT).unwrap(), 0.2 + 0.25);
+        assert!((by_rpm.get(&LogField::STFT).unwrap()[10] - expected).abs() < 1e-3);
+    }
+}
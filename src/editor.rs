@@ -0,0 +1,335 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    ops::RangeInclusive,
+    path::Path,
+};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEventKind},
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use crate::{
+    atr::{resolution, write_atr},
+    smooth::Kernel,
+    table::CalibrationTable,
+};
+
+/// Step of the `+` and `-` keys, in percent of the cell value.
+const NUDGE_PCT: f32 = 1.0;
+/// Kernel of the `s` key.
+const SELECTION_KERNEL: Kernel = Kernel::MovingAverage { width: 3 };
+/// Lines the editor draws besides the table rows: title, header, help and status.
+const CHROME_LINES: usize = 4;
+/// Key help shown under the table.
+const HELP: &str = "↑↓ move  v select  Enter edit  +/- nudge  s smooth  l lock to stock  r revert  w save  q quit";
+/// ANSI reverse video for the selection, bold for the cursor, and the reset after them.
+const SELECTED: &str = "\x1b[7m";
+const CURSOR: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// What the terminal loop should do after a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Continue,
+    Save,
+    Quit,
+}
+
+/// A proposed table under review: the values being edited next to the stock and proposed ones,
+/// with the samples behind each cell and the cells locked to stock.
+pub struct TableEditor {
+    pub stock: CalibrationTable,
+    pub proposed: CalibrationTable,
+    /// The edited table, saved on `w`.
+    pub table: CalibrationTable,
+    pub hits: Vec<usize>,
+    pub locked: Vec<bool>,
+    pub cursor: usize,
+    /// Other end of the selection, while one is being made.
+    anchor: Option<usize>,
+    /// Text typed into the cell being edited.
+    input: Option<String>,
+    /// First row on screen.
+    scroll: usize,
+    /// Rows moved by Page Up and Page Down, updated to the screen height on each draw.
+    page: usize,
+    pub modified: bool,
+    quit_pending: bool,
+    pub message: String,
+}
+
+impl TableEditor {
+    /// Starts editing `proposed`, compared against `stock`. `hits` may be empty if no logs were given.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tables have no cells or do not share the same axis.
+    pub fn new(stock: CalibrationTable, proposed: CalibrationTable, hits: Vec<usize>) -> io::Result<Self> {
+        if stock.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The tables have no cells to edit"));
+        }
+        if stock.axis != proposed.axis {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The stock and proposed tables do not have the same axis",
+            ));
+        }
+        let cells = stock.axis.len();
+        Ok(TableEditor {
+            table: proposed.clone(),
+            stock,
+            proposed,
+            hits: if hits.len() == cells { hits } else { vec![0; cells] },
+            locked: vec![false; cells],
+            cursor: 0,
+            anchor: None,
+            input: None,
+            scroll: 0,
+            page: 20,
+            modified: false,
+            quit_pending: false,
+            message: String::new(),
+        })
+    }
+
+    /// Returns the selected cells, or the cell under the cursor if nothing is selected.
+    pub fn selection(&self) -> RangeInclusive<usize> {
+        let anchor = self.anchor.unwrap_or(self.cursor);
+        anchor.min(self.cursor)..=anchor.max(self.cursor)
+    }
+
+    /// Returns the change of cell `i` from stock, in percent.
+    pub fn change(&self, i: usize) -> Option<f32> {
+        let stock = self.stock.values[i];
+        (stock.abs() > f32::EPSILON).then(|| (self.table.values[i] / stock - 1.0) * 100.0)
+    }
+
+    /// Applies `edit` to every unlocked cell of the selection and reports how many changed.
+    fn edit_selection(&mut self, verb: &str, edit: impl Fn(&Self, usize) -> f32) {
+        let cells: Vec<usize> = self.selection().filter(|&i| !self.locked[i]).collect();
+        let values: Vec<f32> = cells.iter().map(|&i| edit(self, i)).collect();
+        for (&i, value) in cells.iter().zip(values) {
+            self.table.values[i] = value;
+        }
+        self.modified |= !cells.is_empty();
+        self.message = match cells.len() {
+            0 => "Selected cells are locked".to_string(),
+            1 => format!("{} 1 cell", verb),
+            n => format!("{} {} cells", verb, n),
+        };
+    }
+
+    /// Replaces each selected cell with the mean of itself and its neighbours.
+    fn smooth(&mut self) {
+        let apply: Vec<bool> = (0..self.locked.len()).map(|i| self.selection().contains(&i)).collect();
+        let smoothed = SELECTION_KERNEL.smooth(&self.table.values, &apply);
+        self.edit_selection("Smoothed", |_, i| smoothed[i]);
+    }
+
+    /// Locks the selection to stock, or unlocks it if every selected cell is already locked.
+    /// Unlocked cells keep the stock value until edited or reverted.
+    fn toggle_lock(&mut self) {
+        let lock = self.selection().any(|i| !self.locked[i]);
+        for i in self.selection() {
+            self.locked[i] = lock;
+            if lock && self.table.values[i] != self.stock.values[i] {
+                self.table.values[i] = self.stock.values[i];
+                self.modified = true;
+            }
+        }
+        let count = self.selection().count();
+        self.message = format!("{} {} cell{}", if lock { "Locked" } else { "Unlocked" }, count, if count == 1 { "" } else { "s" });
+    }
+
+    fn move_cursor(&mut self, to: usize) {
+        self.cursor = to.min(self.table.values.len() - 1);
+    }
+
+    /// Handles one key and returns what the terminal loop should do next.
+    pub fn handle(&mut self, key: KeyCode) -> Outcome {
+        if let Some(input) = self.input.as_mut() {
+            match key {
+                KeyCode::Char(c) if c.is_ascii_digit() || c == '.' => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Enter => {
+                    match input.parse::<f32>() {
+                        Ok(value) if value.is_finite() && value >= 0.0 => self.edit_selection("Set", |_, _| value),
+                        _ => self.message = format!("'{}' is not an airflow", input),
+                    }
+                    self.input = None;
+                }
+                KeyCode::Esc => self.input = None,
+                _ => {}
+            }
+            return Outcome::Continue;
+        }
+
+        let quit_pending = std::mem::take(&mut self.quit_pending);
+        match key {
+            KeyCode::Up | KeyCode::Char('k') => self.move_cursor(self.cursor.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Char('j') => self.move_cursor(self.cursor + 1),
+            KeyCode::PageUp => self.move_cursor(self.cursor.saturating_sub(self.page)),
+            KeyCode::PageDown => self.move_cursor(self.cursor + self.page),
+            KeyCode::Home | KeyCode::Char('g') => self.move_cursor(0),
+            KeyCode::End | KeyCode::Char('G') => self.move_cursor(usize::MAX),
+            KeyCode::Char('v') => self.anchor = if self.anchor.is_some() { None } else { Some(self.cursor) },
+            KeyCode::Esc => self.anchor = None,
+            KeyCode::Enter | KeyCode::Char('e') => self.input = Some(String::new()),
+            KeyCode::Char('+' | '=') => self.edit_selection("Raised", |editor, i| editor.table.values[i] * (1.0 + NUDGE_PCT / 100.0)),
+            KeyCode::Char('-') => self.edit_selection("Lowered", |editor, i| editor.table.values[i] * (1.0 - NUDGE_PCT / 100.0)),
+            KeyCode::Char('s') => self.smooth(),
+            KeyCode::Char('l') => self.toggle_lock(),
+            KeyCode::Char('r') => self.edit_selection("Reverted", |editor, i| editor.proposed.values[i]),
+            KeyCode::Char('w') => return Outcome::Save,
+            KeyCode::Char('q') if self.modified && !quit_pending => {
+                self.quit_pending = true;
+                self.message = "Unsaved changes, press q again to quit".to_string();
+            }
+            KeyCode::Char('q') => return Outcome::Quit,
+            _ => {}
+        }
+        Outcome::Continue
+    }
+
+    /// Draws the editor in `height` lines, scrolling so the cursor stays on screen.
+    pub fn render(&mut self, height: usize) -> String {
+        let rows = height.saturating_sub(CHROME_LINES).max(1);
+        self.page = rows;
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + rows {
+            self.scroll = self.cursor + 1 - rows;
+        }
+
+        let unit = self.stock.signal.unit();
+        let mut text = String::new();
+        let _ = writeln!(text, "MAF table editor{}", if self.modified { " (modified)" } else { "" });
+        let _ = writeln!(text, "  {:>9}  {:>9}  {:>9}  {:>8}  {:>6}", format!("MAF ({})", unit), "Stock g/s", "New g/s", "Change", "Hits");
+        let selection = self.selection();
+        for i in self.scroll..(self.scroll + rows).min(self.table.values.len()) {
+            let marker = if self.locked[i] {
+                'L'
+            } else if self.table.values[i] != self.proposed.values[i] {
+                '*'
+            } else {
+                ' '
+            };
+            let change = self.change(i).map_or_else(|| "-".to_string(), |change| format!("{:+.1}%", change));
+            let line = format!(
+                "{}{:>9.3}  {:>9.2}  {:>9.2}  {:>8}  {:>6}",
+                marker, self.stock.axis[i], self.stock.values[i], self.table.values[i], change, self.hits[i]
+            );
+            let style = match (i == self.cursor, self.anchor.is_some() && selection.contains(&i)) {
+                (true, _) => format!("{}{}", CURSOR, SELECTED),
+                (false, true) => SELECTED.to_string(),
+                (false, false) => String::new(),
+            };
+            let _ = writeln!(text, "{}{}{}", style, line, if style.is_empty() { "" } else { RESET });
+        }
+        let _ = writeln!(text, "{}", HELP);
+        match &self.input {
+            Some(input) => {
+                let _ = write!(text, "New g/s for {} cell(s): {}", selection.count(), input);
+            }
+            None => text.push_str(&self.message),
+        }
+        text
+    }
+}
+
+/// Restores the terminal when the editor exits, also on errors.
+struct RawTerminal;
+
+impl RawTerminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+        Ok(RawTerminal)
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Runs `editor` on the terminal until the user quits. Saving writes the edited table to `out`
+/// in the Accesstuner Race layout at the resolution of the stock values, like the table written after a fit.
+pub fn run_editor(mut editor: TableEditor, out: &Path) -> io::Result<()> {
+    let _terminal = RawTerminal::enter()?;
+    loop {
+        let (_, height) = terminal::size()?;
+        // Raw mode does not return the carriage on a line feed
+        let screen = editor.render(height as usize).replace('\n', "\r\n");
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "\x1b[H\x1b[2J{}", screen)?;
+        stdout.flush()?;
+        drop(stdout);
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match editor.handle(key.code) {
+            Outcome::Continue => {}
+            Outcome::Save => {
+                write_atr(out, &editor.table, resolution(&editor.stock))?;
+                editor.modified = false;
+                editor.message = format!("Wrote {}", out.display());
+            }
+            Outcome::Quit => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_smooth_and_lock() {
+        let stock = CalibrationTable::new(vec![1.0, 2.0, 3.0, 4.0], vec![10.0, 20.0, 30.0, 40.0]).unwrap();
+        let proposed = CalibrationTable::new(stock.axis.clone(), vec![11.0, 26.0, 31.0, 44.0]).unwrap();
+        let mut editor = TableEditor::new(stock, proposed, vec![5, 3, 0, 1]).unwrap();
+
+        // Type a value into the second cell
+        editor.handle(KeyCode::Down);
+        for key in [KeyCode::Enter, KeyCode::Char('2'), KeyCode::Char('2'), KeyCode::Char('.'), KeyCode::Char('5'), KeyCode::Enter] {
+            editor.handle(key);
+        }
+        assert_eq!(editor.table.values[1], 22.5);
+        assert_eq!(editor.change(1), Some(12.5));
+
+        // Lock the last cell, then smooth the second to last two: only the unlocked one changes
+        editor.handle(KeyCode::End);
+        editor.handle(KeyCode::Char('l'));
+        assert_eq!(editor.table.values[3], 40.0);
+        for key in [KeyCode::Char('v'), KeyCode::Up, KeyCode::Char('s')] {
+            editor.handle(key);
+        }
+        assert_eq!(editor.selection(), 2..=3);
+        assert!((editor.table.values[2] - (22.5 + 31.0 + 40.0) / 3.0).abs() < 1e-4);
+        assert_eq!(editor.table.values[3], 40.0);
+
+        let screen = editor.render(8);
+        assert_eq!(screen.lines().count(), 8);
+        assert!(screen.contains("L    4.000") && screen.contains("(modified)"));
+
+        assert_eq!(editor.handle(KeyCode::Char('q')), Outcome::Continue);
+        assert_eq!(editor.handle(KeyCode::Char('q')), Outcome::Quit);
+    }
+
+    #[test]
+    fn test_empty_table_is_rejected() {
+        let empty = CalibrationTable { axis: Vec::new(), values: Vec::new(), signal: Default::default() };
+        assert!(TableEditor::new(empty.clone(), empty, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::io::{self, Read, Write};
+use crate::data::LogField;
+
+/// Prompt an ELM327 prints when it is ready for the next command.
+pub const PROMPT: u8 = b'>';
+/// Commands sent after connecting: reset, echo, linefeeds, spaces and headers off, automatic protocol.
+const INIT_COMMANDS: [&str; 6] = ["ATZ", "ATE0", "ATL0", "ATS0", "ATH0", "ATSP0"];
+
+/// A standard mode 01 PID, with the log column it fills.
+#[derive(Debug, Clone, Copy)]
+pub struct Pid {
+    pub code: u8,
+    pub header: &'static str,
+    pub field: LogField,
+    /// Converts the data bytes of a response to the logged value.
+    pub decode: fn(&[u8]) -> Option<f32>,
+    /// Converts a logged value back to data bytes, for the simulator.
+    pub encode: fn(f32) -> Vec<u8>,
+}
+
+/// The PIDs polled by the logger, in column order.
+/// Temperatures are logged in °F, the unit AccessPort logs use, so both kinds of log share IAT bands.
+pub const PIDS: [Pid; 6] = [
+    Pid { code: 0x10, header: "Mass Airflow (g/s)", field: LogField::MASS, decode: |b| word(b).map(|w| w / 100.0), encode: |v| to_word(v * 100.0) },
+    Pid { code: 0x06, header: "Short Term FT (%)", field: LogField::STFT, decode: |b| byte(b).map(|a| a * 100.0 / 128.0 - 100.0), encode: |v| to_byte((v + 100.0) * 128.0 / 100.0) },
+    Pid { code: 0x07, header: "Long Term FT (%)", field: LogField::LTFT, decode: |b| byte(b).map(|a| a * 100.0 / 128.0 - 100.0), encode: |v| to_byte((v + 100.0) * 128.0 / 100.0) },
+    Pid { code: 0x0C, header: "RPM (RPM)", field: LogField::RPM, decode: |b| word(b).map(|w| w / 4.0), encode: |v| to_word(v * 4.0) },
+    Pid { code: 0x0F, header: "Intake Temp. (F)", field: LogField::IAT, decode: |b| byte(b).map(|a| to_fahrenheit(a - 40.0)), encode: |v| to_byte(to_celsius(v) + 40.0) },
+    Pid { code: 0x11, header: "Throttle Position (%)", field: LogField::TPS, decode: |b| byte(b).map(|a| a * 100.0 / 255.0), encode: |v| to_byte(v * 255.0 / 100.0) },
+];
+
+/// Column header of the MAF voltage, which no standard PID carries.
+pub const MAF_VOLTAGE_HEADER: &str = "MAF Voltage (V)";
+
+/// Converts the data bytes of a MAF voltage response, read as millivolts.
+pub fn decode_maf_voltage(bytes: &[u8]) -> Option<f32> {
+    word(bytes).map(|mv| mv / 1000.0)
+}
+
+/// Converts a MAF voltage to the data bytes of a response, in millivolts.
+pub fn encode_maf_voltage(volts: f32) -> Vec<u8> {
+    to_word(volts * 1000.0)
+}
+
+fn to_fahrenheit(celsius: f32) -> f32 {
+    celsius * 9.0 / 5.0 + 32.0
+}
+
+fn to_celsius(fahrenheit: f32) -> f32 {
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+fn byte(bytes: &[u8]) -> Option<f32> {
+    bytes.first().map(|&a| a as f32)
+}
+
+fn word(bytes: &[u8]) -> Option<f32> {
+    match bytes {
+        [a, b, ..] => Some((*a as f32) * 256.0 + *b as f32),
+        _ => None,
+    }
+}
+
+fn to_byte(value: f32) -> Vec<u8> {
+    vec![value.round().clamp(0.0, 255.0) as u8]
+}
+
+fn to_word(value: f32) -> Vec<u8> {
+    let word = value.round().clamp(0.0, 65535.0) as u16;
+    word.to_be_bytes().to_vec()
+}
+
+/// An ELM327-compatible adapter on a serial port or socket.
+pub struct Elm327<T> {
+    port: T,
+}
+
+impl<T: Read + Write> Elm327<T> {
+    /// Wraps a connected port. Call `init` before querying.
+    pub fn new(port: T) -> Self {
+        Elm327 { port }
+    }
+
+    /// Resets the adapter and sets it up for compact, echo-free responses.
+    pub fn init(&mut self) -> io::Result<()> {
+        for command in INIT_COMMANDS {
+            self.command(command)?;
+        }
+        Ok(())
+    }
+
+    /// Sends one command and returns the response lines, without echo or prompt.
+    ///
+    /// # Errors
+    ///
+    /// Returns `UnexpectedEof` if the adapter closes the connection.
+    pub fn command(&mut self, command: &str) -> io::Result<Vec<String>> {
+        self.port.write_all(format!("{}\r", command).as_bytes())?;
+        self.port.flush()?;
+
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        loop {
+            match self.port.read(&mut byte) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Adapter closed the connection")),
+                Ok(_) if byte[0] == PROMPT => break,
+                Ok(_) => response.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(String::from_utf8_lossy(&response)
+            .split(['\r', '\n'])
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && *line != command)
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Sends a hex request such as `010C` and returns the data bytes of the positive response,
+    /// or `None` if the vehicle does not answer it.
+    pub fn query(&mut self, request: &str) -> io::Result<Option<Vec<u8>>> {
+        let lines = self.command(request)?;
+        Ok(parse_response(request, &lines))
+    }
+}
+
+/// Finds the positive response to `request` among the response lines and returns its data bytes.
+/// A positive response repeats the request with 0x40 added to the mode.
+pub fn parse_response(request: &str, lines: &[String]) -> Option<Vec<u8>> {
+    let mode = u8::from_str_radix(request.get(..2)?, 16).ok()?;
+    let expected = format!("{:02X}{}", mode + 0x40, &request[2..]).to_uppercase();
+    lines.iter().find_map(|line| {
+        let hex: String = line.chars().filter(|c| !c.is_whitespace()).collect::<String>().to_uppercase();
+        let data = hex.strip_prefix(&expected)?;
+        (0..data.len() / 2).map(|i| u8::from_str_radix(&data[i * 2..i * 2 + 2], 16).ok()).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_round_trip_and_response_parsing() {
+        for (pid, value) in PIDS.iter().zip([12.34, -3.125, 4.6875, 2750.0, 95.0, 40.0]) {
+            let decoded = (pid.decode)(&(pid.encode)(value)).unwrap();
+            assert!((decoded - value).abs() < 0.3, "{}: {} != {}", pid.header, decoded, value);
+        }
+        assert_eq!(decode_maf_voltage(&encode_maf_voltage(2.345)), Some(2.345));
+
+        let lines = vec!["SEARCHING...".to_string(), "41 0C 2A F8".to_string()];
+        assert_eq!(parse_response("010C", &lines), Some(vec![0x2A, 0xF8]));
+        assert_eq!(parse_response("221001", &["6210010929".to_string()]), Some(vec![0x09, 0x29]));
+        assert_eq!(parse_response("0110", &["NO DATA".to_string()]), None);
+    }
+
+    #[test]
+    fn test_intake_temperature_in_fahrenheit() {
+        let iat = PIDS.iter().find(|pid| pid.field == LogField::IAT).unwrap();
+        // 25 °C is byte 65
+        assert_eq!((iat.decode)(&[65]), Some(77.0));
+        assert_eq!((iat.encode)(77.0), vec![65]);
+    }
+}
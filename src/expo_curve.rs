@@ -1,18 +1,59 @@
 use std::io;
 use wgpu::util::{DeviceExt, BufferInitDescriptor};
 use bytemuck::cast_slice;
-use naga;
+use crate::data::MafSignal;
 
-struct Range {
-    min: f32,
-    max: f32,
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+/// The grid searched for `a` and `n`, and the divisor applied to X before fitting.
+/// Scaling keeps `a` in a range a linear grid can resolve when X is large, e.g. frequencies in Hz.
+pub struct SearchSpace {
+    pub a: Range,
+    pub n: Range,
+    pub x_scale: f32,
 }
 
 const PRECISION: u32 = 4096;
-const RANGE_A: Range = Range { min: 0.0, max: 16.0 };
-const RANGE_N: Range = Range { min: 0.0, max: 16.0 };
+/// Search space for MAF voltage, 0-5 V.
+pub const VOLTAGE_SEARCH: SearchSpace = SearchSpace {
+    a: Range { min: 0.0, max: 16.0 },
+    n: Range { min: 0.0, max: 16.0 },
+    x_scale: 1.0,
+};
+/// Search space for MAF frequency, fitted in kHz.
+pub const FREQUENCY_SEARCH: SearchSpace = SearchSpace {
+    a: Range { min: 0.0, max: 64.0 },
+    n: Range { min: 0.0, max: 8.0 },
+    x_scale: 1000.0,
+};
+
+impl SearchSpace {
+    /// Returns the default search space for a MAF signal.
+    pub fn for_signal(signal: MafSignal) -> &'static Self {
+        match signal {
+            MafSignal::Voltage => &VOLTAGE_SEARCH,
+            MafSignal::Frequency => &FREQUENCY_SEARCH,
+        }
+    }
+
+    /// Returns the `a, n` pair the shader evaluates for result `index`: `a` steps along a row and
+    /// `n` down the rows, each in `PRECISION` increments of its range starting at the minimum.
+    pub fn point(&self, index: usize) -> (f32, f32) {
+        let increment_a = (self.a.max - self.a.min) / PRECISION as f32;
+        let increment_n = (self.n.max - self.n.min) / PRECISION as f32;
+        let (i, j) = (index / PRECISION as usize, index % PRECISION as usize);
+        (self.a.min + j as f32 * increment_a, self.n.min + i as f32 * increment_n)
+    }
+}
 
-pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
+/// Fits `Y = aX ^ n` by evaluating every `a, n` pair of `space` on the GPU,
+/// minimising the squared errors weighted by `weights`. The returned `a` applies to unscaled X.
+pub async fn run(x_data: &[f32], y_data: &[f32], weights: &[f32], space: &SearchSpace) -> io::Result<(f32, f32)> {
+    let x_data: Vec<f32> = x_data.iter().map(|&x| x / space.x_scale).collect();
+    let x_data = x_data.as_slice();
     let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
         backends: wgpu::Backends::all(),
         dx12_shader_compiler: Default::default(),
@@ -61,6 +102,11 @@ pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
         contents: cast_slice(y_data),
         usage: wgpu::BufferUsages::STORAGE,
     });
+    let weight_buffer = device.create_buffer_init(&BufferInitDescriptor {
+        label: Some("Weight Buffer"),
+        contents: cast_slice(weights),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
     // Create a buffer to hold the results
     let results_buffer = device.create_buffer(&wgpu::BufferDescriptor {
         label: Some("Results Buffer"),
@@ -77,8 +123,8 @@ pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
     });
     // Create the increment_data array
     let increment_data = [
-        RANGE_A.min, RANGE_A.max,
-        RANGE_N.min, RANGE_N.max,
+        space.a.min, space.a.max,
+        space.n.min, space.n.max,
         PRECISION as f32
     ];
     // Create the increment_buffer
@@ -129,7 +175,17 @@ pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
                     min_binding_size: wgpu::BufferSize::new((std::mem::size_of::<f32>() * 5) as _),
                 },
                 count: None,
-            },            
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<f32>() as _),
+                },
+                count: None,
+            },
         ],
         label: Some("bind_group_layout"),
     });
@@ -168,7 +224,15 @@ pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
                     offset: 0,
                     size: wgpu::BufferSize::new((std::mem::size_of::<f32>() * 5) as _),
                 }),
-            },            
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &weight_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(std::mem::size_of_val(weights) as _),
+                }),
+            },
         ],
         label: Some("bind_group"),
     });
@@ -225,15 +289,9 @@ pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
     let mut best_a = 0.0;
     let mut best_n = 0.0;
 
-    let increment_a = (RANGE_A.max - RANGE_A.min) / (PRECISION as f32 - 1.0);
-    let increment_n = (RANGE_N.max - RANGE_N.min) / (PRECISION as f32 - 1.0);
-
     // Linear search: Find the best_a and best_n with the lowest mse
     for (index, &mse) in result_vec.iter().enumerate() {
-        let i = index / PRECISION as usize;
-        let j = index % PRECISION as usize;
-        let a = RANGE_A.min + j as f32 * increment_a;
-        let n = RANGE_N.min + i as f32 * increment_n;
+        let (a, n) = space.point(index);
 
         if mse < min_mse {
             min_mse = mse;
@@ -242,7 +300,24 @@ pub async fn run(x_data: &[f32], y_data: &[f32]) -> io::Result<(f32, f32)> {
         }
     }
 
+    // Convert `a` back to unscaled X
+    let best_a = best_a / space.x_scale.powf(best_n);
     println!("Optimized Coefficient (a): {}, Optimized Exponent (n): {}, Minimum Mean Squared Error (MSE): {}", best_a, best_n, min_mse);
     device.stop_capture();
     Ok((best_a, best_n))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_point_matches_shader_grid() {
+        let step = 16.0 / PRECISION as f32;
+        assert_eq!(VOLTAGE_SEARCH.point(0), (0.0, 0.0));
+        assert_eq!(VOLTAGE_SEARCH.point(1), (step, 0.0));
+        assert_eq!(VOLTAGE_SEARCH.point(PRECISION as usize), (0.0, step));
+        // Like the shader, the grid stops one increment short of the maximum
+        let last = (PRECISION * PRECISION) as usize - 1;
+        assert_eq!(VOLTAGE_SEARCH.point(last), (16.0 - step, 16.0 - step));
+    }
+}
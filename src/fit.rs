@@ -0,0 +1,91 @@
+use std::io;
+use serde::{Deserialize, Serialize};
+use crate::{
+    data::MafSignal,
+    expo_curve::{run, SearchSpace},
+    session::{deduplicate, Sample},
+    table::CalibrationTable,
+};
+
+/// Statistics describing one curve fit and the trims of the samples it was fitted on.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FitReport {
+    pub a: f32,
+    pub n: f32,
+    pub mse: f32,
+    pub samples: usize,
+    pub mean_trim: f32,
+    pub mean_abs_trim: f32,
+}
+
+/// The deduplicated points a curve was fitted on, the fitted values at those points, and the report.
+pub struct Fit {
+    pub signal: MafSignal,
+    pub x: Vec<f32>,
+    pub y: Vec<f32>,
+    pub y_fit: Vec<f32>,
+    pub report: FitReport,
+}
+
+impl Fit {
+    /// Evaluates the fitted curve `Y = aX ^ n` at `x`.
+    pub fn eval(&self, x: f32) -> f32 {
+        self.report.a * x.powf(self.report.n)
+    }
+
+    /// Produces a new table from `base`.
+    /// Cells inside the signal range covered by the samples take the fitted value;
+    /// cells outside it keep the base value, since there is no data to correct them with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `base` is scaled on a different signal than the fitted samples.
+    pub fn apply(&self, base: &CalibrationTable) -> io::Result<CalibrationTable> {
+        if base.signal != self.signal {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Table axis is in {} but the logs are in {}", base.signal.unit(), self.signal.unit()),
+            ));
+        }
+        let values = base.axis.iter().zip(&base.values)
+            .map(|(&x, &value)| if self.covers(x) { self.eval(x) } else { value })
+            .collect();
+        Ok(CalibrationTable { axis: base.axis.clone(), values, signal: base.signal })
+    }
+
+    /// Returns `true` if `x` lies within the signal range of the fitted points.
+    pub fn covers(&self, x: f32) -> bool {
+        let min_x = self.x.iter().copied().fold(f32::MAX, f32::min);
+        let max_x = self.x.iter().copied().fold(f32::MIN, f32::max);
+        x >= min_x && x <= max_x
+    }
+}
+
+/// Fits `Y = aX ^ n` on the deduplicated `samples` and summarises the result.
+/// The search space for `a, n` depends on the MAF `signal` the samples are in.
+/// Each point counts by the weight of its sample, in the fit and in the report.
+pub async fn fit(samples: &[Sample], signal: MafSignal) -> io::Result<Fit> {
+    let (x, y, weights) = deduplicate(samples);
+    if x.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No samples to fit"));
+    }
+
+    println!("Starting curve fitting");
+    let (a, n) = run(&x, &y, &weights, SearchSpace::for_signal(signal)).await?;
+    // Compute the fitted y values using the optimized parameters
+    let y_fit: Vec<f32> = x.iter().map(|&x| a * x.powf(n)).collect();
+    let mse = y.iter().zip(&y_fit).zip(&weights)
+        .map(|((&y, &y_fit), &w)| w * (y - y_fit) * (y - y_fit))
+        .sum::<f32>() / weights.iter().sum::<f32>().max(f32::EPSILON);
+
+    let total = samples.iter().map(|s| s.weight).sum::<f32>().max(f32::EPSILON);
+    let report = FitReport {
+        a,
+        n,
+        mse,
+        samples: x.len(),
+        mean_trim: samples.iter().map(|s| s.weight * s.trim).sum::<f32>() / total,
+        mean_abs_trim: samples.iter().map(|s| s.weight * s.trim.abs()).sum::<f32>() / total,
+    };
+    Ok(Fit { signal, x, y, y_fit, report })
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+use crate::{
+    bins::{BinStats, Bins},
+    data::MafSignal,
+    session::Sample,
+};
+
+/// Width of one intake temperature band, in the unit the log records temperature in.
+pub const IAT_BAND_WIDTH: f32 = 20.0;
+/// Minimum samples a band needs to appear in the compensation table.
+const MIN_BAND_HITS: usize = 5;
+/// Correlation between temperature and trim above which the trims are taken to depend on it.
+const MIN_CORRELATION: f32 = 0.3;
+/// Trim change (in %) across the logged temperature range that is worth compensating.
+const MIN_EFFECT_PCT: f32 = 2.0;
+
+/// Corrected trims of one intake temperature band.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IatBand {
+    pub lo: f32,
+    pub hi: f32,
+    pub samples: usize,
+    pub mean_trim: f32,
+    /// Mean trim relative to the other bands at the same MAF signal: the part of the trim
+    /// that the MAF scaling cannot remove.
+    pub offset: f32,
+}
+
+/// Corrected trims grouped by intake temperature band per MAF signal bin.
+///
+/// Within one signal bin the MAF scaling applies the same correction at every temperature,
+/// so trims that still vary with temperature there point to a missing IAT compensation
+/// rather than a MAF scaling error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IatAnalysis {
+    pub signal: MafSignal,
+    pub bins: Bins,
+    pub bands: Vec<IatBand>,
+    /// Trim statistics indexed by signal bin, then band.
+    pub cells: Vec<Vec<BinStats>>,
+    /// Trim change (%) per degree within a signal bin.
+    pub slope: f32,
+    pub correlation: f32,
+}
+
+impl IatAnalysis {
+    /// Groups `samples` into bands of `band_width` degrees.
+    /// Returns `None` if no sample carries an intake temperature.
+    pub fn new(samples: &[Sample], signal: MafSignal, band_width: f32) -> Option<Self> {
+        let bins = Bins::for_signal(signal);
+        let samples: Vec<(usize, &Sample)> = samples.iter()
+            .filter(|s| s.iat.is_finite() && s.trim.is_finite())
+            .filter_map(|s| bins.index(s.x).map(|i| (i, s)))
+            .collect();
+        let min = samples.iter().map(|(_, s)| s.iat).reduce(f32::min)?;
+        let max = samples.iter().map(|(_, s)| s.iat).reduce(f32::max)?;
+        let first = (min / band_width).floor();
+        let count = ((max / band_width).floor() - first) as usize + 1;
+        let band_of = |iat: f32| ((iat / band_width).floor() - first) as usize;
+
+        // Mean trim and temperature per signal bin, to look at temperature effects within a bin
+        let mut bin_trim = vec![BinStats::default(); bins.count];
+        let mut bin_iat = vec![BinStats::default(); bins.count];
+        for &(i, s) in &samples {
+            bin_trim[i].add(s.trim);
+            bin_iat[i].add(s.iat);
+        }
+
+        let mut cells = vec![vec![BinStats::default(); count]; bins.count];
+        let mut band_trim = vec![BinStats::default(); count];
+        let mut band_offset = vec![BinStats::default(); count];
+        let (mut cov, mut var_iat, mut var_trim) = (0.0, 0.0, 0.0);
+        for &(i, s) in &samples {
+            let band = band_of(s.iat);
+            let residual = s.trim - bin_trim[i].mean().unwrap_or(0.0);
+            let deviation = s.iat - bin_iat[i].mean().unwrap_or(0.0);
+            cells[i][band].add(s.trim);
+            band_trim[band].add(s.trim);
+            band_offset[band].add(residual);
+            cov += (deviation * residual) as f64;
+            var_iat += (deviation * deviation) as f64;
+            var_trim += (residual * residual) as f64;
+        }
+
+        let bands = (0..count)
+            .map(|band| {
+                let lo = (first + band as f32) * band_width;
+                IatBand {
+                    lo,
+                    hi: lo + band_width,
+                    samples: band_trim[band].hits,
+                    mean_trim: band_trim[band].mean().unwrap_or(f32::NAN),
+                    offset: band_offset[band].mean().unwrap_or(f32::NAN),
+                }
+            })
+            .collect();
+        let slope = if var_iat > 0.0 { (cov / var_iat) as f32 } else { 0.0 };
+        let correlation = if var_iat > 0.0 && var_trim > 0.0 { (cov / (var_iat * var_trim).sqrt()) as f32 } else { 0.0 };
+        Some(IatAnalysis { signal, bins, bands, cells, slope, correlation })
+    }
+
+    /// Returns the bands with enough samples for a trustworthy offset.
+    fn populated(&self) -> impl Iterator<Item = &IatBand> {
+        self.bands.iter().filter(|band| band.samples >= MIN_BAND_HITS)
+    }
+
+    /// Returns the trim change (%) the slope accounts for across the populated bands.
+    pub fn effect(&self) -> f32 {
+        let centers: Vec<f32> = self.populated().map(|band| (band.lo + band.hi) / 2.0).collect();
+        match (centers.first(), centers.last()) {
+            (Some(lo), Some(hi)) => (self.slope * (hi - lo)).abs(),
+            _ => 0.0,
+        }
+    }
+
+    /// Returns `true` if the trims vary with intake temperature enough to need compensation.
+    pub fn depends_on_temperature(&self) -> bool {
+        self.correlation.abs() >= MIN_CORRELATION && self.effect() >= MIN_EFFECT_PCT
+    }
+
+    /// Returns the suggested IAT compensation as `(band center, airflow correction %)` points,
+    /// to apply together with the MAF scaling fitted from the same logs.
+    pub fn compensation(&self) -> Vec<(f32, f32)> {
+        self.populated().map(|band| ((band.lo + band.hi) / 2.0, band.offset)).collect()
+    }
+}
+
+impl fmt::Display for IatAnalysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Trims by intake temperature:")?;
+        writeln!(f, "  {:>11}  {:>7}  {:>9}  {:>7}", "IAT", "Samples", "Mean trim", "Offset")?;
+        for band in self.bands.iter().filter(|band| band.samples > 0) {
+            writeln!(
+                f,
+                "  {:>5.0}-{:<5.0}  {:>7}  {:>8.2}%  {:>+6.2}%",
+                band.lo, band.hi, band.samples, band.mean_trim, band.offset
+            )?;
+        }
+
+        write!(f, "  {:>7} ", self.signal.unit())?;
+        for band in &self.bands {
+            write!(f, " {:>7.0}", band.lo)?;
+        }
+        writeln!(f)?;
+        for (i, row) in self.cells.iter().enumerate().filter(|(_, row)| row.iter().any(|s| s.hits > 0)) {
+            write!(f, "  {:>7.2} ", self.bins.range(i).0)?;
+            for cell in row {
+                match cell.mean() {
+                    Some(trim) => write!(f, " {:>+6.1}%", trim)?,
+                    None => write!(f, " {:>7}", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+
+        if self.depends_on_temperature() {
+            writeln!(
+                f,
+                "Trims depend on intake temperature: {:+.3}% per degree (r = {:.2}), {:.1}% across the logged range.",
+                self.slope, self.correlation, self.effect()
+            )
+        } else {
+            writeln!(
+                f,
+                "Trims do not depend on intake temperature (r = {:.2}); the remaining error is in the MAF scaling.",
+                self.correlation
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(trim: impl Fn(f32, f32) -> f32) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for i in 0..20 {
+            let x = 1.0 + i as f32 * 0.1;
+            for iat in [50.0, 70.0, 90.0, 110.0] {
+                let trim = trim(x, iat);
+                samples.push(Sample { trim, iat, ..Sample::point(x, 10.0 * (1.0 + trim / 100.0)) });
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn test_temperature_dependence() {
+        // Hotter air is denser per MAF reading than the sensor assumes: 0.1% per degree
+        let hot = IatAnalysis::new(&samples(|_, iat| (iat - 80.0) * 0.1), MafSignal::Voltage, IAT_BAND_WIDTH).unwrap();
+        assert!(hot.depends_on_temperature());
+        assert!((hot.slope - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_compensation() {
+        let hot = IatAnalysis::new(&samples(|_, iat| (iat - 80.0) * 0.1), MafSignal::Voltage, IAT_BAND_WIDTH).unwrap();
+        let compensation = hot.compensation();
+        assert_eq!(compensation.len(), 4);
+        assert_eq!(compensation[0].0, 50.0);
+        assert!((compensation[0].1 + 3.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_scaling_error_is_not_temperature() {
+        // Trims that only follow the MAF signal are a scaling problem
+        let scaling = IatAnalysis::new(&samples(|x, _| x * 3.0), MafSignal::Voltage, IAT_BAND_WIDTH).unwrap();
+        assert!(!scaling.depends_on_temperature());
+        assert!(scaling.to_string().contains("do not depend"));
+    }
+
+    #[test]
+    fn test_no_intake_temperature() {
+        let no_iat: Vec<Sample> = samples(|_, _| 0.0).into_iter().map(|s| Sample { iat: f32::NAN, ..s }).collect();
+        assert!(IatAnalysis::new(&no_iat, MafSignal::Voltage, IAT_BAND_WIDTH).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+use crate::{
+    bins::{BinStats, Bins, MAF_MAX_HZ, MAF_MAX_V, MAF_MIN_V},
+    correction::{correct, CorrectionConfig},
+    data::{LogData, MafSignal},
+    session::Sample,
+};
+
+/// Width of one display bin along a voltage signal; coarser than the table so it fits a terminal.
+const DISPLAY_BIN_WIDTH_V: f32 = 0.25;
+/// Width of one display bin along a frequency signal.
+const DISPLAY_BIN_WIDTH_HZ: f32 = 750.0;
+/// Samples a display bin needs before it counts as driven enough.
+pub const TARGET_HITS: usize = 50;
+/// Width of the hit bar, in characters.
+const BAR_WIDTH: usize = 20;
+/// Shortest time between two redraws.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Running per-bin trims and corrected airflow, updated as rows arrive during a logging session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RollingBins {
+    pub signal: MafSignal,
+    pub bins: Bins,
+    pub trims: Vec<BinStats>,
+    pub airflow: Vec<BinStats>,
+    pub rows: usize,
+}
+
+impl RollingBins {
+    /// Starts empty display bins spanning the range of `signal`.
+    pub fn new(signal: MafSignal) -> Self {
+        let bins = match signal {
+            MafSignal::Voltage => Bins::new(MAF_MIN_V, MAF_MAX_V, DISPLAY_BIN_WIDTH_V),
+            MafSignal::Frequency => Bins::new(0.0, MAF_MAX_HZ, DISPLAY_BIN_WIDTH_HZ),
+        };
+        RollingBins {
+            signal,
+            bins,
+            trims: vec![BinStats::default(); bins.count],
+            airflow: vec![BinStats::default(); bins.count],
+            rows: 0,
+        }
+    }
+
+    /// Adds one corrected sample.
+    pub fn add(&mut self, sample: &Sample) {
+        if let Some(i) = self.bins.index(sample.x) {
+            self.trims[i].add(sample.trim);
+            self.airflow[i].add(sample.y);
+        }
+    }
+
+    /// Corrects newly arrived rows and adds them, leaving out blacklisted MAF signal ranges.
+    pub fn add_rows(&mut self, data: &LogData, config: &CorrectionConfig) -> io::Result<()> {
+        self.rows += data.len();
+        for sample in correct(data, 0, config)? {
+            if !config.blacklist.excludes_signal(sample.x) {
+                self.add(&sample);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for RollingBins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = self.signal.unit();
+        writeln!(f, "{} rows, bins need {} samples", self.rows, TARGET_HITS)?;
+        writeln!(f, "{:>15}  {:<width$}  {:>5}  {:>7}  {:>8}", "MAF", "Coverage", "Hits", "Trim", "Airflow", width = BAR_WIDTH)?;
+        for (i, (trims, airflow)) in self.trims.iter().zip(&self.airflow).enumerate() {
+            let (lo, hi) = self.bins.range(i);
+            let filled = (trims.hits * BAR_WIDTH / TARGET_HITS).min(BAR_WIDTH);
+            let bar = format!("{}{}", "█".repeat(filled), "·".repeat(BAR_WIDTH - filled));
+            write!(f, "{:>6.2}-{:<6.2}{:>2}  {}  {:>5}", lo, hi, unit, bar, trims.hits)?;
+            match (trims.mean(), airflow.mean()) {
+                (Some(trim), Some(airflow)) => writeln!(f, "  {:>+6.1}%  {:>8.2}", trim, airflow)?,
+                _ => writeln!(f, "  {:>7}  {:>8}", "-", "-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Redraws a `RollingBins` in place on the terminal, at most once per `REFRESH_INTERVAL`.
+#[derive(Debug, Default)]
+pub struct LiveDisplay {
+    last: Option<Instant>,
+}
+
+impl LiveDisplay {
+    /// Redraws `bins` unless the last redraw was too recent. `force` redraws regardless.
+    pub fn refresh(&mut self, bins: &RollingBins, force: bool) -> io::Result<()> {
+        if !force && self.last.is_some_and(|last| last.elapsed() < REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        self.last = Some(Instant::now());
+        let mut out = io::stdout().lock();
+        // Home the cursor and clear the screen, then draw over it
+        write!(out, "\x1b[H\x1b[2J{}", bins)?;
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blacklist::Blacklist,
+        data::LogField,
+        testing::{log, row},
+    };
+
+    fn rolling() -> RollingBins {
+        let mut bins = RollingBins::new(MafSignal::Voltage);
+        let rows = [(2.1, 4.0), (2.2, 2.0), (3.9, -5.0)];
+        let data = log(rows.map(|(x, stft)| row(x).with(LogField::STFT, stft).with(LogField::LTFT, 1.0)));
+        bins.add_rows(&data, &CorrectionConfig::default()).unwrap();
+        bins
+    }
+
+    #[test]
+    fn test_rolling_bins() {
+        let bins = rolling();
+        assert_eq!(bins.rows, 3);
+        assert_eq!(bins.trims[8].hits, 2);
+        assert_eq!(bins.trims[8].mean(), Some(4.0));
+        assert!((bins.airflow[15].mean().unwrap() - 19.2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_rolling_bins_skip_blacklisted_signal() {
+        let mut bins = RollingBins::new(MafSignal::Voltage);
+        let config = CorrectionConfig { blacklist: Blacklist::parse("signal 2.0 2.15").unwrap(), ..CorrectionConfig::default() };
+        bins.add_rows(&log([row(2.1), row(2.2)]), &config).unwrap();
+        assert_eq!(bins.rows, 2);
+        assert_eq!(bins.trims[8].hits, 1);
+    }
+
+    #[test]
+    fn test_display() {
+        let text = rolling().to_string();
+        assert_eq!(text.lines().count(), 2 + 20);
+        assert!(text.contains("  2.00-2.25"));
+        assert!(text.contains("+4.0%"));
+    }
+}
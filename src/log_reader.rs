@@ -0,0 +1,283 @@
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+use csv::{ByteRecord, ReaderBuilder};
+use crate::{
+    blacklist::Blacklist,
+    data::{Channel, DeviceInfo, LogData, LogField, MafSignal},
+};
+
+/// Malformed rows reported one by one before the rest are only counted.
+const MAX_WARNINGS: usize = 10;
+/// Prefix of the metadata field an AccessPort adds to the header or footer of its logs.
+const AP_INFO: &str = "AP Info:";
+/// Windows-1252 characters for bytes 0x80-0x9F; the other bytes map to the same Latin-1 code point.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+/// Reads an OBD2 CSV log into a `LogData`.
+///
+/// Every column is loaded as a channel named by its header. Header names, without their unit,
+/// are also matched against the `LogField` aliases, so both the `Name (unit)` layout of `log1.csv` and the
+/// bare `Name` layout of `log2.csv` supply the well-known fields.
+/// The MAF column header decides whether the log carries a voltage or a frequency signal.
+/// Columns excluded by the `blacklist` are not parsed, so another column may supply their field.
+/// Rows where any required field is missing or unparsable are skipped with a warning naming
+/// their line, which keeps every field vector aligned to the same sample index.
+/// Other columns read as NaN where unparsable. Rows whose time is missing or earlier than
+/// the row before are skipped too, so the time column is monotonic.
+///
+/// The file is streamed one record at a time and its text is never held whole, but every parsed
+/// value is kept: memory grows by 4 bytes per row per column, which for a typical AccessPort log
+/// of short numeric fields is close to the size of the file itself.
+/// Fields may be quoted; text that is not valid UTF-8 is read as Windows-1252, which is what
+/// AccessManager writes. An `AP Info:[...]` field in the header or on a footer line is parsed
+/// into the log's `device`.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be opened or read, is empty, or lacks a required header.
+pub fn read_log<P: AsRef<Path>>(path: P, blacklist: &Blacklist) -> io::Result<LogData> {
+    let path = path.as_ref();
+    let log = File::open(path).map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            io::Error::new(e.kind(), format!("CSV log file {} not found. Ensure the path is correct and the file exists.", path.display()))
+        } else {
+            e
+        }
+    })?;
+    parse_log(log, &path.display().to_string(), blacklist)
+}
+
+/// Parses a CSV log from `reader`, see `read_log`. `name` identifies the log in messages.
+pub fn parse_log<R: Read>(reader: R, name: &str, blacklist: &Blacklist) -> io::Result<LogData> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+
+    // Extract the headers from the first record of the CSV
+    let mut record = ByteRecord::new();
+    if !reader.read_byte_record(&mut record)? {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is empty", name)));
+    }
+    let header = HeaderMap::new(&record, name, blacklist)?;
+    let mut device = header.device.clone();
+
+    // Process each record in the CSV, keeping only rows where every required field parses
+    let mut log_data = header.empty_log();
+    let mut row = Vec::with_capacity(header.columns.len());
+    let mut skipped = 0;
+    while reader.read_byte_record(&mut record)? {
+        if let Some(info) = footer_info(&record) {
+            device = Some(info);
+            continue;
+        }
+        match header.parse_row(&record, &mut row) {
+            Ok(()) => log_data.push_values(&row),
+            Err(problem) => {
+                skipped += 1;
+                if skipped <= MAX_WARNINGS {
+                    let line = record.position().map_or(0, |position| position.line());
+                    eprintln!("Warning: {}: line {}: {}, row skipped", name, line, problem);
+                }
+            }
+        }
+    }
+    if skipped > MAX_WARNINGS {
+        eprintln!("Warning: {}: {} more malformed rows skipped", name, skipped - MAX_WARNINGS);
+    }
+    let unordered = log_data.make_monotonic();
+    if unordered > 0 {
+        eprintln!("Warning: {}: {} rows out of time order skipped", name, unordered);
+    }
+
+    log_data.device = device;
+    Ok(log_data)
+}
+
+/// The mapping from a log's header record to channels and `LogField` columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderMap {
+    pub headers: Vec<String>,
+    /// Record index of each loaded column, in channel order, and the well-known field it supplies.
+    pub columns: Vec<(usize, Option<LogField>)>,
+    pub signal: MafSignal,
+    pub device: Option<DeviceInfo>,
+}
+
+impl HeaderMap {
+    /// Maps the columns of a header record, skipping those excluded by the `blacklist`.
+    /// Every other named column is loaded; the first column matching a `LogField` supplies it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the log if a required header is missing.
+    pub fn new(record: &ByteRecord, name: &str, blacklist: &Blacklist) -> io::Result<Self> {
+        let headers: Vec<String> = record.iter().map(|header| decode(header).into_owned()).collect();
+        let device = headers.iter().find_map(|header| parse_ap_info(header));
+
+        // Create a mapping from each column to its channel, and from each LogField to its column
+        let mut columns = Vec::new();
+        let mut found = HashSet::new();
+        for (i, header) in headers.iter().enumerate() {
+            if header.trim().is_empty() || parse_ap_info(header).is_some() || blacklist.excludes_column(header) {
+                continue;
+            }
+            let field = LogField::variants().iter()
+                .find(|field| field.matches_header(header))
+                .filter(|&&field| found.insert(field))
+                .copied();
+            columns.push((i, field));
+        }
+
+        // Ensure all required headers (defined by LogField variants) are present in the CSV
+        let missing_headers: Vec<&str> = LogField::variants().iter()
+            .filter(|field| field.is_required() && !found.contains(field))
+            .map(|field| field.to_header())
+            .collect();
+
+        if !missing_headers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: the following headers were not found: {}", name, missing_headers.join(", ")),
+            ));
+        }
+
+        let signal = columns.iter()
+            .find(|(_, field)| *field == Some(LogField::MAFV))
+            .and_then(|&(index, _)| MafSignal::from_header(&headers[index]))
+            .unwrap_or_default();
+        Ok(HeaderMap { headers, columns, signal, device })
+    }
+
+    /// Returns an empty `LogData` with the signal and a channel per column of this header.
+    pub fn empty_log(&self) -> LogData {
+        let mut log_data = LogData::empty();
+        log_data.signal = self.signal;
+        for &(index, field) in &self.columns {
+            log_data.add_channel(Channel::from_header(&self.headers[index]), field);
+        }
+        log_data
+    }
+
+    /// Parses one data record into `row`, a value per channel. Values read as NaN where
+    /// unparsable, except for required fields.
+    ///
+    /// # Errors
+    ///
+    /// Returns a description of the problem if a required field is missing or unparsable.
+    pub fn parse_row(&self, record: &ByteRecord, row: &mut Vec<f32>) -> Result<(), String> {
+        row.clear();
+        for &(index, field) in &self.columns {
+            let required = field.filter(|field| field.is_required());
+            match (record.get(index).map(parse_field), required) {
+                (Some(Some(value)), _) => row.push(value),
+                (_, None) => row.push(f32::NAN),
+                (None, Some(_)) => return Err(format!("truncated after {} of {} columns", record.len(), self.headers.len())),
+                (Some(None), Some(field)) => return Err(format!("'{}' is not a number", field.to_header())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a field as UTF-8, or as Windows-1252 if it is not valid UTF-8.
+pub fn decode(field: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(field) {
+        Ok(text) => Cow::Borrowed(text),
+        Err(_) => Cow::Owned(field.iter()
+            .map(|&byte| match byte {
+                0x80..=0x9f => WINDOWS_1252[(byte - 0x80) as usize],
+                _ => char::from(byte),
+            })
+            .collect()),
+    }
+}
+
+/// Parses `AP Info:[AP3-MAZ-002 v1.7.3.1-16781][USDM 2011-2013 MAZDASPEED3.ptm]` into the
+/// device, its firmware and the platform of the map it was logging with.
+/// Returns `None` if `field` is not an AP Info field.
+pub fn parse_ap_info(field: &str) -> Option<DeviceInfo> {
+    let info = field.trim().strip_prefix(AP_INFO)?;
+    let mut groups = info.split('[').filter_map(|group| group.split_once(']')).map(|(group, _)| group.trim());
+    let first = groups.next()?;
+    let (device, firmware) = match first.split_once(char::is_whitespace) {
+        Some((device, firmware)) => (device, Some(firmware.trim().to_string())),
+        None => (first, None),
+    };
+    let platform = groups.next().map(|platform| platform.trim_end_matches(".ptm").to_string());
+    Some(DeviceInfo { device: device.to_string(), firmware, platform })
+}
+
+/// Returns the device of an `AP Info:[...]` footer line, or `None` if `record` is a row of data.
+pub fn footer_info(record: &ByteRecord) -> Option<DeviceInfo> {
+    record.get(0).and_then(|first| parse_ap_info(&decode(first)))
+}
+
+/// Parses one numeric field, ignoring surrounding whitespace.
+fn parse_field(field: &[u8]) -> Option<f32> {
+    std::str::from_utf8(field).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_quoted_truncated_and_non_utf8() {
+        let mut log = b"Time,\"MAF Voltage (V)\",Mass Airflow (g/s),Short Term FT,Long Term FT,Intake Valve Adv. (\xb0)\n".to_vec();
+        assert_eq!(decode(b"Spark Adv. (\xb0) \x80"), "Spark Adv. (°) €");
+        log.extend_from_slice(b"0.0,\"1.50\",10,1.0,2.0,3\n");
+        log.extend_from_slice(b"0.1,abc,11,1.0,2.0,3\n");
+        log.extend_from_slice(b"0.2,1.60,12,1.0,2.0,\n");
+        log.extend_from_slice(b"0.3,1.70,13");
+
+        let data = parse_log(&log[..], "test.csv", &Blacklist::default()).unwrap();
+        assert_eq!(data.signal, MafSignal::Voltage);
+        assert_eq!(data.get(&LogField::MAFV).unwrap(), &vec![1.5, 1.6]);
+        assert_eq!(data.get(&LogField::TIME).unwrap(), &vec![0.0, 0.2]);
+        // Every column is a channel, with the unit split off its header
+        assert_eq!(data.channels().len(), 6);
+        let advance = data.channel("Intake Valve Adv.").unwrap();
+        assert_eq!(advance.unit.as_deref(), Some("°"));
+        assert_eq!(advance.values[0], 3.0);
+        assert!(advance.values[1].is_nan());
+        assert_eq!(data.channel("MAF Voltage").unwrap().header(), "MAF Voltage (V)");
+        assert_eq!(data.channel("Long Term FT").unwrap().unit, None);
+
+        assert_eq!(data.device, None);
+
+        log.extend_from_slice(b"\nAP Info:[AP3-MAZ-002 v1.7.3.1-16781][USDM 2011-2013 MAZDASPEED3.ptm]\n");
+        let data = parse_log(&log[..], "test.csv", &Blacklist::default()).unwrap();
+        assert_eq!(data.device, Some(DeviceInfo {
+            device: "AP3-MAZ-002".to_string(),
+            firmware: Some("v1.7.3.1-16781".to_string()),
+            platform: Some("USDM 2011-2013 MAZDASPEED3".to_string()),
+        }));
+        assert_eq!(data.get(&LogField::MAFV).unwrap().len(), 2);
+
+        let missing = parse_log(&b"Time,Mass Airflow\n"[..], "test.csv", &Blacklist::default()).unwrap_err();
+        assert!(missing.to_string().contains("MAF Voltage"));
+    }
+
+    #[test]
+    fn test_decoy_headers_are_not_fields() {
+        let log = b"Injector Time (ms),RPM Target,Time (sec),MAF Voltage (V),mass airflow,Short Term FT,Long Term FT,rpm (RPM),\
+            Intake Temp.\n4.5,3000,0.0,1.5,10,1.0,2.0,2500,70\n";
+        let data = parse_log(&log[..], "test.csv", &Blacklist::default()).unwrap();
+        assert_eq!(data.get(&LogField::TIME).unwrap(), &vec![0.0]);
+        assert_eq!(data.get(&LogField::RPM).unwrap(), &vec![2500.0]);
+        assert_eq!(data.get(&LogField::MASS).unwrap(), &vec![10.0]);
+        assert_eq!(data.get(&LogField::IAT).unwrap(), &vec![70.0]);
+        // The decoys are still loaded, by their own names
+        assert_eq!(data.channel("Injector Time").unwrap().values, vec![4.5]);
+        assert_eq!(data.channel("RPM Target").unwrap().values, vec![3000.0]);
+    }
+}
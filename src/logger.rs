@@ -0,0 +1,132 @@
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    time::{Duration, Instant},
+};
+use csv::Writer;
+use crate::{
+    correction::CorrectionConfig,
+    data::{LogData, LogField, MafSignal},
+    elm327::{decode_maf_voltage, Elm327, MAF_VOLTAGE_HEADER, PIDS},
+    live::{LiveDisplay, RollingBins},
+};
+
+/// Default baud rate of ELM327 serial adapters.
+pub const DEFAULT_BAUD: u32 = 38400;
+/// How long to wait for the adapter to answer a command.
+const ADAPTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A connection to an adapter: a serial port or a TCP socket.
+pub trait Port: Read + Write {}
+
+impl<T: Read + Write> Port for T {}
+
+/// Live logging settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoggerConfig {
+    /// `tcp:HOST:PORT` for a WiFi adapter or the simulator, otherwise a serial device path.
+    pub adapter: String,
+    pub baud: u32,
+    /// Adapter request for the MAF voltage, e.g. a mode 22 request for the ECU's MAF sensor input.
+    /// Its response is read as millivolts. No standard PID reports the voltage, and a log without
+    /// it cannot be fitted.
+    pub maf_voltage: String,
+    /// Stop after this many rows, otherwise log until the adapter disconnects.
+    pub rows: Option<usize>,
+    /// Show the samples and trims per MAF bin while logging.
+    pub display: bool,
+}
+
+/// Opens the adapter named in `config`.
+pub fn connect(config: &LoggerConfig) -> io::Result<Box<dyn Port>> {
+    if let Some(address) = config.adapter.strip_prefix("tcp:") {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(ADAPTER_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        return Ok(Box::new(stream));
+    }
+    let port = serialport::new(&config.adapter, config.baud)
+        .timeout(ADAPTER_TIMEOUT)
+        .open()
+        .map_err(|e| io::Error::other(format!("{}: {}", config.adapter, e)))?;
+    Ok(Box::new(port))
+}
+
+/// Polls the adapter and writes one CSV row per round of PIDs to `out`, in the layout
+/// `read_log` reads. Each row is flushed as it is written, so the log can be followed while
+/// it grows. Values the vehicle does not report are left empty.
+/// With `display`, the rows are also shown per MAF bin as they arrive.
+/// Returns the number of rows written.
+pub fn log_to_csv<T: Read + Write, W: Write>(adapter: &mut Elm327<T>, out: W, config: &LoggerConfig) -> io::Result<usize> {
+    let mut writer = Writer::from_writer(out);
+    let mut header = vec!["Time (sec)", MAF_VOLTAGE_HEADER];
+    header.extend(PIDS.iter().map(|pid| pid.header));
+    writer.write_record(&header)?;
+    writer.flush()?;
+
+    let mut rolling = RollingBins::new(MafSignal::Voltage);
+    let mut display = LiveDisplay::default();
+    let start = Instant::now();
+    let mut rows = 0;
+    while config.rows.is_none_or(|limit| rows < limit) {
+        let time = start.elapsed().as_secs_f32();
+        let values = match poll_row(adapter, config) {
+            Ok(values) => values,
+            Err(e) if disconnected(&e) => break,
+            Err(e) => return Err(e),
+        };
+        let mut record = vec![format!("{:.3}", time)];
+        record.extend(values.iter().map(|&(_, value)| {
+            if value.is_finite() { format!("{:.3}", value) } else { String::new() }
+        }));
+        writer.write_record(&record)?;
+        writer.flush()?;
+        rows += 1;
+
+        if config.display {
+            let mut row = LogData::default();
+            row.push_row(&values);
+            rolling.add_rows(&row, &CorrectionConfig::default())?;
+            display.refresh(&rolling, false)?;
+        }
+    }
+    if config.display {
+        display.refresh(&rolling, true)?;
+    }
+    Ok(rows)
+}
+
+/// Returns `true` for errors that mean the adapter went away rather than failed.
+fn disconnected(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Queries the MAF voltage and every PID once, returning a value per column after the time.
+/// Values the vehicle does not report are NaN.
+fn poll_row<T: Read + Write>(adapter: &mut Elm327<T>, config: &LoggerConfig) -> io::Result<Vec<(LogField, f32)>> {
+    let maf_voltage = adapter.query(&config.maf_voltage)?.and_then(|bytes| decode_maf_voltage(&bytes));
+    let mut values = vec![(LogField::MAFV, maf_voltage.unwrap_or(f32::NAN))];
+    for pid in &PIDS {
+        let value = adapter.query(&format!("01{:02X}", pid.code))?.and_then(|bytes| (pid.decode)(&bytes));
+        values.push((pid.field, value.unwrap_or(f32::NAN)));
+    }
+    Ok(values)
+}
+
+/// Connects to the adapter and logs to the CSV file at `out` until it disconnects
+/// or the row limit is reached.
+pub fn run_logger(config: &LoggerConfig, out: &Path) -> io::Result<()> {
+    let mut adapter = Elm327::new(connect(config)?);
+    adapter.init()?;
+    if !config.display {
+        println!("Logging from {} to {}", config.adapter, out.display());
+    }
+    let rows = log_to_csv(&mut adapter, File::create(out)?, config)?;
+    println!("Logged {} rows", rows);
+    Ok(())
+}
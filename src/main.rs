@@ -1,154 +1,329 @@
 // Import necessary modules and libraries
+mod afr_table;
+mod atr;
+mod bins;
+mod blacklist;
+mod cli;
+mod correction;
 mod data;
+mod elm327;
+mod delay;
+mod editor;
 mod csv_out;
 mod expo_curve;
+mod fit;
+mod iat;
+mod live;
+mod log_reader;
+mod logger;
+mod plot;
+mod project;
+mod report;
+mod resample;
+mod rules;
+mod session;
+mod simulator;
+mod smooth;
+mod table;
+mod term_plot;
+#[cfg(test)]
+mod testing;
+mod trim_map;
+mod watch;
 
 use std::{
-    collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{self, BufRead, BufReader},
-    path::Path,
+    env,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process,
     time::Instant,
 };
-use data::{F32, LogData, LogField};
-use csv_out::write_to_csv;
-use expo_curve::run;
+use atr::{resolution, write_atr};
+use cli::{Command, USAGE};
+use correction::CorrectionConfig;
+use csv_out::{read_from_csv, write_to_csv};
+use editor::{run_editor, TableEditor};
+use fit::fit;
+use blacklist::Blacklist;
+use log_reader::read_log;
+use logger::run_logger;
+use plot::{write_heatmap, write_svg};
+use iat::{IatAnalysis, IAT_BAND_WIDTH};
+use project::Project;
+use report::{cell_hits, HtmlReport, InputFile};
+use rules::RuleReport;
+use session::{Sample, Session, SessionReport};
+use smooth::{cell_airflow, Smoothing, SmoothingReport};
+use table::CalibrationTable;
+use term_plot::{plot_fit, terminal_width};
+use trim_map::{MapAxis, TrimMap};
 
 /// Main function for the program.
 ///
-/// This function processes the OBD2 CSV log by:
-/// 1. Reading the CSV file and extracting its headers.
-/// 2. Verifying that all required headers are present.
-/// 3. Parsing the CSV file line-by-line and extracting relevant data.
-/// 4. Combining and correcting the extracted data.
-/// 5. Deduplicating the X and Y values for curve fitting.
-/// 6. Exporting the pre-corrected and post-corrected data to separate CSV files.
+/// Parses the command line and dispatches to the requested command.
+/// Without a command, the given logs (or `./data/log1.csv`) are fitted.
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let start = Instant::now();
-    let mut deduplicated_x = Vec::new();
-    let mut deduplicated_y = Vec::new();
-    // Check if stock.csv exists
-    if Path::new("./data/stock.csv").exists() {
-        if let Ok(lines) = read_lines("./data/stock.csv") {
-            for line in lines {
-                if let Ok(record) = line {
-                    let values: Vec<&str> = record.split(',').collect();
-                    deduplicated_x.push(values[0].parse().unwrap());
-                    deduplicated_y.push(values[1].parse().unwrap());
-                }
-            }
+    let command = match Command::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Fit { stock, logs, correction, plot, smoothing, map_by } => {
+            fit_logs(stock.as_deref(), logs, &correction, plot, smoothing.as_ref(), map_by).await?
+        }
+        Command::Init { project, stock } => init_project(&project, &stock)?,
+        Command::Next { project, from, logs, correction, smoothing } => {
+            next_revision(&project, from, &logs, &correction, smoothing.as_ref()).await?
         }
+        Command::History { project } => print!("{}", Project::load(project)?.history()),
+        Command::Export { project, out, rev } => export_revision(&project, &out, rev)?,
+        Command::Log { out, config } => run_logger(&config, &out)?,
+        Command::Simulate { log, port, speed } => simulate(&log, &port, speed)?,
+        Command::Watch { log, correction } => watch::watch(&log, correction)?,
+        Command::Plot { pre, post } => plot_csv(&pre, &post)?,
+        Command::Edit { stock, proposed, logs, out, correction } => edit_table(&stock, &proposed, &logs, &out, &correction)?,
+    }
+
+    let duration = start.elapsed();
+    println!("Time elapsed: {:?}", duration);
+    Ok(())
+}
+
+/// Fits one or more OBD2 CSV logs by:
+/// 1. Loading every log (or `./data/log1.csv` if none are given).
+/// 2. Verifying that all required headers are present in each log.
+/// 3. Dropping rows that match an exclusion rule, then correcting the extracted data from
+///    the fuel trims, or the wideband in open loop, tagging each sample with its source log.
+/// 4. Reporting coverage per source and warning where sources disagree.
+/// 5. Grouping the trims by intake temperature and, if they depend on it,
+///    exporting a suggested IAT compensation table.
+/// 6. Deduplicating the X and Y values of the union and fitting the curve.
+/// 7. Exporting the pre-corrected and post-corrected data to separate CSV files,
+///    a plot of the samples, the stock table, the fitted curve and the residuals,
+///    and an offline HTML report of the run.
+///    With `plot`, the samples and fitted curve are also plotted in the terminal.
+/// 8. If a stock table is given, exporting the corrected table in Accesstuner Race layout at the
+///    resolution of the stock values. With `smoothing`, the table is built cell by cell from the
+///    corrected airflow and then smoothed, rather than taken from the fitted curve.
+async fn fit_logs(
+    stock: Option<&Path>,
+    mut logs: Vec<PathBuf>,
+    correction: &CorrectionConfig,
+    plot: bool,
+    smoothing: Option<&Smoothing>,
+    map_by: MapAxis,
+) -> io::Result<()> {
+    let (samples, signal, reports, trim_map) = if Path::new("./data/stock.csv").exists() {
+        // Fit the stock table itself
+        logs = vec![PathBuf::from("./data/stock.csv")];
+        let stock = CalibrationTable::load("./data/stock.csv")?;
+        let samples = stock.axis.iter().zip(&stock.values)
+            .map(|(&x, &y)| Sample::point(x, y))
+            .collect();
+        (samples, stock.signal, None, None)
     } else {
-        let log = fs::File::open("./data/log1.csv").map_err(|e| {
-            if e.kind() == io::ErrorKind::NotFound {
-                io::Error::new(e.kind(), "CSV log file not found. Ensure the path is correct and the file exists.")
-            } else {
-                e
-            }
-        })?;
-        
-        let reader = BufReader::new(log);
-        let mut lines = reader.lines();
-
-        // Extract the headers from the first line of the CSV
-        let headers_line = lines.next().unwrap()?;
-        let headers: Vec<&str> = headers_line.split(',').collect();
-
-        // Create a mapping from headers to their corresponding column indices
-        let mut indices = HashMap::new();
-        for (i, header) in headers.iter().enumerate() {
-            if let Some(field) = LogField::variants().iter().find(|&&field| header.contains(field.to_header())) {
-                indices.insert(field.to_header(), i);
+        if logs.is_empty() {
+            logs.push(PathBuf::from("./data/log1.csv"));
+        }
+        let (samples, session, rules) = load_samples(&logs, correction)?;
+        if let Some(iat) = IatAnalysis::new(&samples, session.signal, IAT_BAND_WIDTH) {
+            print!("{}", iat);
+            if iat.depends_on_temperature() {
+                let (temperatures, corrections): (Vec<f32>, Vec<f32>) = iat.compensation().into_iter().unzip();
+                write_to_csv("iat-compensation.csv", &temperatures, &corrections)?;
+                println!("Wrote suggested IAT compensation to iat-compensation.csv");
             }
         }
+        let trim_map = TrimMap::new(&samples, session.signal, map_by);
+        if let Some(map) = &trim_map {
+            print!("{}", map);
+            map.write_csv("trim-map.csv")?;
+            write_heatmap("trim-map.svg", map)?;
+            println!("Wrote trim-map.csv and trim-map.svg");
+        }
+        (samples, session.signal, Some((session, rules)), trim_map)
+    };
 
-        // Ensure all required headers (defined by LogField variants) are present in the CSV
-        let required_headers: Vec<&str> = LogField::variants().iter().map(|variant| variant.to_header()).collect();
-        let missing_headers: Vec<&str> = required_headers.iter()
-            .filter(|&&key| !indices.contains_key(key))
-            .cloned()
-            .collect();
+    let fit = fit(&samples, signal).await?;
+    // Export the deduplicated data and the fitted data for comparison
+    write_to_csv("pre-correction.csv", &fit.x, &fit.y)?;
+    write_to_csv("post-correction.csv", &fit.x, &fit.y_fit)?;
+    let stock_table = stock.map(CalibrationTable::load).transpose()?;
+    write_svg("maf-plot.svg", &samples, stock_table.as_ref(), &fit)?;
+    println!("Wrote maf-plot.svg");
+    if plot {
+        print!("{}", plot_fit(&fit.x, &fit.y, &fit.y_fit, terminal_width(), io::stdout().is_terminal()));
+    }
 
-        if !missing_headers.is_empty() {
-            let missing_list = missing_headers.join(", ");
-            panic!("The following headers were not found: {}", missing_list);
+    // The new table, smoothed if asked
+    let (table, smoothed) = match &stock_table {
+        Some(stock) => {
+            let (table, report) = new_table(&fit, stock, smoothing, &samples)?;
+            (Some(table), report)
         }
+        None => (None, None),
+    };
 
+    let mut inputs = logs.iter().map(InputFile::read).collect::<io::Result<Vec<_>>>()?;
+    inputs.extend(stock.map(InputFile::read).transpose()?);
+    HtmlReport {
+        samples: &samples,
+        fit: &fit,
+        stock: stock_table.as_ref(),
+        table: table.as_ref(),
+        smoothing: smoothed.as_ref(),
+        inputs,
+        session: reports.as_ref().map(|(session, _)| session),
+        rules: reports.as_ref().map(|(_, rules)| rules),
+        trim_map: trim_map.as_ref(),
+        correction,
+    }
+    .write("maf-report.html")?;
+    println!("Wrote maf-report.html");
 
-        // Initialize a structure to hold the extracted log data
-        let mut log_data = LogData::default();
-
-        // Use a HashSet to ensure unique key-value combinations
-        let mut seen = HashSet::new();
+    if let (Some(table), Some(stock)) = (table, &stock_table) {
+        write_atr("maf-table.txt", &table, resolution(stock))?;
+        println!("Wrote maf-table.txt");
+    }
+    Ok(())
+}
 
-        // Process each line in the CSV, extracting and organizing relevant data
-        for line in lines {
-            let line = line?;
-            let columns: Vec<&str> = line.split(',').collect();
+/// Creates a new project file whose revision 0 is the stock table at `stock`.
+fn init_project(project: &Path, stock: &Path) -> io::Result<()> {
+    if project.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", project.display()),
+        ));
+    }
+    let table = CalibrationTable::load(stock)?;
+    if table.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} contains no table cells", stock.display()),
+        ));
+    }
+    Project::new(table).save(project)?;
+    println!("Created project {} from {}", project.display(), stock.display());
+    Ok(())
+}
 
-            for &key in required_headers.iter() {
-                if let Some(&index) = indices.get(key) {
-                    if let Ok(value) = columns[index].parse::<f32>() {
-                        if let Some(field) = LogField::from_header(key) {
-                            log_data.push(field, value, &mut seen);
-                        }
-                    }
-                }
-            }
-        }
+/// Generates the next revision of a project from logs driven on revision K, starting from revision K's
+/// table. K defaults to the latest revision; the new revision is always numbered after the latest.
+async fn next_revision(
+    path: &Path,
+    from: Option<usize>,
+    logs: &[PathBuf],
+    correction: &CorrectionConfig,
+    smoothing: Option<&Smoothing>,
+) -> io::Result<()> {
+    let mut project = Project::load(path)?;
+    let based_on = from.unwrap_or_else(|| project.latest());
+    let base = project.table(based_on).cloned().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("Revision {} does not exist", based_on))
+    })?;
 
-        // Deduplicate X and Y values in preparation for curve fitting
-        let mut seen_xy = HashSet::new();
+    let (samples, session, _) = load_samples(logs, correction)?;
+    let fit = fit(&samples, session.signal).await?;
+    let (table, _) = new_table(&fit, &base, smoothing, &samples)?;
+    let logs = logs.iter().map(|log| log.display().to_string()).collect();
+    let number = project.push(based_on, logs, table, fit.report).number;
+    project.save(path)?;
+    println!("Revision {} generated, based on revision {}", number, based_on);
+    Ok(())
+}
 
-        // Combine STFT and LTFT values to compute the combined fuel trim correction factor
-        let ft_combine: Vec<f32> = log_data.get(&LogField::STFT).unwrap().iter().zip(log_data.get(&LogField::LTFT).unwrap())
-        .map(|(&stft, &ltft)| stft + ltft)
+/// Applies `fit` to `base`, the table the logs were driven on.
+///
+/// With `smoothing`, each cell with samples instead takes the mean corrected airflow of the samples
+/// nearest it, and those binned values are smoothed, printing what smoothing changed.
+/// Cells without samples keep the fitted value inside the fitted range and the base value outside
+/// it, and are not smoothed.
+fn new_table(fit: &fit::Fit, base: &CalibrationTable, smoothing: Option<&Smoothing>, samples: &[Sample]) -> io::Result<(CalibrationTable, Option<SmoothingReport>)> {
+    let fitted = fit.apply(base)?;
+    let Some(smoothing) = smoothing else {
+        return Ok((fitted, None));
+    };
+    let airflow = cell_airflow(&base.axis, samples);
+    let values = fitted.values.iter().zip(&airflow)
+        .map(|(&fitted, airflow)| airflow.unwrap_or(fitted))
         .collect();
+    let binned = CalibrationTable { values, ..fitted };
+    let locked: Vec<bool> = airflow.iter().map(Option::is_none).collect();
+    let (table, report) = smoothing.apply_with_report(&binned, &locked, samples)?;
+    print!("{}", report);
+    Ok((table, Some(report)))
+}
 
-        // Correct the MAF data using the combined fuel trim values
-        let maf_cor: Vec<f32> = ft_combine.iter().zip(log_data.get(&LogField::MASS).unwrap())
-        .map(|(&ft, &maf)| ft + maf)
-        .collect();
+/// Writes revision `rev` (default latest) of a project in Accesstuner Race layout,
+/// at the resolution of its stock table.
+fn export_revision(path: &Path, out: &Path, rev: Option<usize>) -> io::Result<()> {
+    let project = Project::load(path)?;
+    let rev = rev.unwrap_or_else(|| project.latest());
+    let table = project.table(rev).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::NotFound, format!("Revision {} does not exist", rev))
+    })?;
+    write_atr(out, table, resolution(&project.stock))?;
+    println!("Wrote revision {} to {}", rev, out.display());
+    Ok(())
+}
 
-        // Deduplicate data in preparation for curve fitting
-        for (x_val, y_val) in log_data.get(&LogField::MAFV).unwrap().iter().zip(maf_cor.iter()) {
-            let unique_key = (F32(*x_val), F32(*y_val));
-            if !seen_xy.contains(&unique_key) {
-                seen_xy.insert(unique_key);
-                deduplicated_x.push(*x_val);
-                deduplicated_y.push(*y_val);
-            }
-        }
+/// Plots the x/y data of a previous fit from its pre-correction and post-correction CSV files.
+fn plot_csv(pre: &Path, post: &Path) -> io::Result<()> {
+    let (x, y) = read_from_csv(&pre.to_string_lossy())?;
+    let (post_x, y_fit) = read_from_csv(&post.to_string_lossy())?;
+    if post_x != x {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} and {} are not from the same fit", pre.display(), post.display()),
+        ));
     }
-    // Export the deduplicated data for further analysis
-    write_to_csv("pre-correction.csv", &deduplicated_x, &deduplicated_y)?;
-
-    // Call the run function to get the corrected y data
-    println!("Starting curve fitting");
-    let (best_a, best_n) = run(&deduplicated_x, &deduplicated_y).await?;
-    // Compute the fitted y values using the optimized parameters
-    let y_fit: Vec<f32> = deduplicated_x.iter()
-        .map(|&x| best_a * x.powf(best_n))
-        .collect();
-
-    // Export the fitted data for comparison
-    write_to_csv("post-correction.csv", &deduplicated_x, &y_fit)?;
-    let duration = start.elapsed();
-    println!("Time elapsed: {:?}", duration);
+    print!("{}", plot_fit(&x, &y, &y_fit, terminal_width(), io::stdout().is_terminal()));
     Ok(())
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where P: AsRef<Path>, {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+/// Opens the table editor on a proposed table. Hits per cell come from `logs`, if any are given.
+fn edit_table(stock: &Path, proposed: &Path, logs: &[PathBuf], out: &Path, correction: &CorrectionConfig) -> io::Result<()> {
+    let stock = CalibrationTable::load(stock)?;
+    let proposed = CalibrationTable::load(proposed)?;
+    let hits = if logs.is_empty() {
+        Vec::new()
+    } else {
+        let (samples, _, _) = load_samples(logs, correction)?;
+        cell_hits(&stock.axis, &samples)
+    };
+    run_editor(TableEditor::new(stock, proposed, hits)?, out)
+}
+
+/// Replays a recorded log as an ELM327 on a TCP address or a new pseudo-terminal.
+fn simulate(log: &Path, port: &str, speed: f32) -> io::Result<()> {
+    let data = read_log(log, &Blacklist::default())?;
+    match port.strip_prefix("tcp:") {
+        Some(address) => simulator::serve_tcp(data, address, speed),
+        None => simulator::serve_pty(data, speed),
+    }
+}
+
+/// Loads a session from `logs`, prints its coverage report and the rows dropped by exclusion rules,
+/// and returns the tagged samples with both reports.
+fn load_samples(logs: &[PathBuf], correction: &CorrectionConfig) -> io::Result<(Vec<Sample>, SessionReport, RuleReport)> {
+    let session = Session::load(logs, &correction.blacklist)?;
+    let (samples, dropped) = session.samples(correction)?;
+    let report = session.report(&samples);
+    print!("{}{}", dropped, report);
+    Ok((samples, report, dropped))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Instant;
+    use expo_curve::{run, VOLTAGE_SEARCH};
     use rand::Rng;
 
     #[tokio::test]
@@ -165,7 +340,7 @@ mod tests {
 
         for _ in 0..num_trials {
             let start = Instant::now();
-            let _ = run(&deduplicated_x, &deduplicated_y).await.unwrap();
+            let _ = run(&deduplicated_x, &deduplicated_y, &[1.0; 100], &VOLTAGE_SEARCH).await.unwrap();
             let duration = start.elapsed().as_millis();
             total_duration += duration;
             min_duration = min_duration.min(duration);
@@ -0,0 +1,387 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io,
+    path::Path,
+};
+use crate::{
+    bins::{BinStats, Bins},
+    data::MafSignal,
+    fit::Fit,
+    session::Sample,
+    table::CalibrationTable,
+    trim_map::TrimMap,
+};
+
+/// Width of the whole image, in pixels.
+const WIDTH: f32 = 900.0;
+/// Height of the curve panel.
+const CURVE_HEIGHT: f32 = 480.0;
+/// Height of the residual panel.
+const RESIDUAL_HEIGHT: f32 = 200.0;
+/// Space around the plotting areas for ticks, labels and the legend.
+const MARGIN_LEFT: f32 = 70.0;
+const MARGIN_RIGHT: f32 = 20.0;
+const MARGIN_TOP: f32 = 30.0;
+const PANEL_GAP: f32 = 60.0;
+const MARGIN_BOTTOM: f32 = 50.0;
+/// Number of tick intervals aimed for along an axis.
+const TICKS: usize = 6;
+/// More samples than this are thinned evenly, to keep the file small enough to open.
+const MAX_POINTS: usize = 5000;
+/// Points the fitted curve is drawn through.
+const CURVE_STEPS: usize = 200;
+/// Size of one trim map cell, in pixels.
+const CELL_WIDTH: f32 = 44.0;
+const CELL_HEIGHT: f32 = 24.0;
+/// Width kept right of the trim map for the colour scale.
+const SCALE_WIDTH: f32 = 90.0;
+/// Trim (%) at which the trim map colours saturate.
+const TRIM_LIMIT: f32 = 10.0;
+
+/// How a series is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Points,
+    Line,
+    Dashed,
+}
+
+/// One named set of points on a panel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Series {
+    pub label: &'static str,
+    pub color: &'static str,
+    pub style: Style,
+    pub points: Vec<(f32, f32)>,
+}
+
+/// A plotting area sharing the X axis with the others, with its own Y axis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Panel {
+    pub y_label: &'static str,
+    pub height: f32,
+    pub series: Vec<Series>,
+    /// Draw a line at Y = 0, e.g. for residuals.
+    pub zero_line: bool,
+}
+
+/// The panels showing a fit: the corrected samples with the stock table, the fitted curve and
+/// the binned means on top, and the residuals of the samples from the fitted curve below.
+/// The stock table is left out if it is scaled on a different signal.
+pub fn fit_panels(samples: &[Sample], stock: Option<&CalibrationTable>, fit: &Fit) -> Vec<Panel> {
+    let step = samples.len().div_ceil(MAX_POINTS).max(1);
+    let shown: Vec<&Sample> = samples.iter().step_by(step).collect();
+    let (min_x, max_x) = extent(samples.iter().map(|s| s.x)).unwrap_or((0.0, 1.0));
+
+    let mut curve = vec![Series {
+        label: "Corrected samples",
+        color: "#4c78a8",
+        style: Style::Points,
+        points: shown.iter().map(|s| (s.x, s.y)).collect(),
+    }];
+    if let Some(stock) = stock.filter(|stock| stock.signal == fit.signal) {
+        curve.push(Series {
+            label: "Stock table",
+            color: "#888888",
+            style: Style::Dashed,
+            points: stock.axis.iter().copied().zip(stock.values.iter().copied()).collect(),
+        });
+    }
+    curve.push(Series {
+        label: "Binned mean",
+        color: "#f58518",
+        style: Style::Line,
+        points: binned_means(samples, fit.signal, |s| s.y),
+    });
+    curve.push(Series {
+        label: "Fitted curve",
+        color: "#e45756",
+        style: Style::Line,
+        points: (0..=CURVE_STEPS)
+            .map(|i| min_x + (max_x - min_x) * i as f32 / CURVE_STEPS as f32)
+            .map(|x| (x, fit.eval(x)))
+            .collect(),
+    });
+
+    let residuals = vec![
+        Series {
+            label: "Sample - fit",
+            color: "#4c78a8",
+            style: Style::Points,
+            points: shown.iter().map(|s| (s.x, s.y - fit.eval(s.x))).collect(),
+        },
+        Series {
+            label: "Binned mean",
+            color: "#f58518",
+            style: Style::Line,
+            points: binned_means(samples, fit.signal, |s| s.y - fit.eval(s.x)),
+        },
+    ];
+
+    vec![
+        Panel { y_label: "Airflow (g/s)", height: CURVE_HEIGHT, series: curve, zero_line: false },
+        Panel { y_label: "Residual (g/s)", height: RESIDUAL_HEIGHT, series: residuals, zero_line: true },
+    ]
+}
+
+/// Returns the mean of `value` over the samples in each populated table bin, at the bin centre.
+fn binned_means(samples: &[Sample], signal: MafSignal, value: impl Fn(&Sample) -> f32) -> Vec<(f32, f32)> {
+    let bins = Bins::for_signal(signal);
+    let mut stats = vec![BinStats::default(); bins.count];
+    for sample in samples {
+        if let Some(i) = bins.index(sample.x) {
+            stats[i].add(value(sample));
+        }
+    }
+    stats.iter().enumerate()
+        .filter_map(|(i, stats)| {
+            let (lo, hi) = bins.range(i);
+            stats.mean().map(|mean| ((lo + hi) / 2.0, mean))
+        })
+        .collect()
+}
+
+/// Returns the smallest and largest finite value, or `None` if there is none.
+fn extent(values: impl Iterator<Item = f32>) -> Option<(f32, f32)> {
+    values.filter(|v| v.is_finite()).fold(None, |range, v| match range {
+        None => Some((v, v)),
+        Some((lo, hi)) => Some((lo.min(v), hi.max(v))),
+    })
+}
+
+/// Returns round tick values covering `min..=max`, about `TICKS` intervals apart.
+pub fn ticks(min: f32, max: f32) -> Vec<f32> {
+    let span = (max - min).max(f32::EPSILON);
+    let raw = span / TICKS as f32;
+    let magnitude = 10f32.powf(raw.log10().floor());
+    let step = [1.0, 2.0, 2.5, 5.0, 10.0].iter()
+        .map(|m| m * magnitude)
+        .find(|&step| step >= raw)
+        .unwrap_or(10.0 * magnitude);
+    let first = (min / step).floor() as i64;
+    let last = (max / step).ceil() as i64;
+    (first..=last).map(|i| i as f32 * step).collect()
+}
+
+/// Formats a tick value with only as many decimals as the tick step needs.
+fn tick_label(value: f32, step: f32) -> String {
+    let decimals = (0..4)
+        .find(|&d| {
+            let scaled = step * 10f32.powi(d as i32);
+            (scaled - scaled.round()).abs() < 1e-3
+        })
+        .unwrap_or(4);
+    format!("{:.*}", decimals, value)
+}
+
+/// Renders `panels` stacked on a shared X axis labelled `x_label`, as an SVG document.
+/// The X range follows the points of the first series of the first panel, so reference lines
+/// such as a full stock table do not squash the data; other series are clipped to it.
+pub fn render_svg(panels: &[Panel], x_label: &str) -> String {
+    let x_range = panels.first()
+        .and_then(|panel| panel.series.first())
+        .and_then(|series| extent(series.points.iter().map(|p| p.0)))
+        .unwrap_or((0.0, 1.0));
+    let x_ticks = ticks(x_range.0, x_range.1);
+    let (x_min, x_max) = (x_ticks[0], *x_ticks.last().unwrap());
+    let plot_width = WIDTH - MARGIN_LEFT - MARGIN_RIGHT;
+    let to_px = |x: f32| MARGIN_LEFT + (x - x_min) / (x_max - x_min).max(f32::EPSILON) * plot_width;
+
+    let height = MARGIN_TOP + panels.iter().map(|p| p.height).sum::<f32>()
+        + PANEL_GAP * panels.len().saturating_sub(1) as f32 + MARGIN_BOTTOM;
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="12">"#, w = WIDTH, h = height);
+    let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+
+    let mut top = MARGIN_TOP;
+    for (index, panel) in panels.iter().enumerate() {
+        let bottom = top + panel.height;
+        let in_range = |p: &&(f32, f32)| p.0 >= x_min && p.0 <= x_max;
+        let y_range = extent(panel.series.iter().flat_map(|s| s.points.iter().filter(in_range).map(|p| p.1)))
+            .map(|(lo, hi)| if panel.zero_line { (lo.min(0.0), hi.max(0.0)) } else { (lo, hi) })
+            .unwrap_or((0.0, 1.0));
+        let y_ticks = ticks(y_range.0, y_range.1);
+        let (y_min, y_max) = (y_ticks[0], *y_ticks.last().unwrap());
+        let to_py = |y: f32| bottom - (y - y_min) / (y_max - y_min).max(f32::EPSILON) * panel.height;
+
+        // Grid, ticks and axis labels
+        let y_step = y_ticks.get(1).map_or(1.0, |next| next - y_ticks[0]);
+        for &y in &y_ticks {
+            let py = to_py(y);
+            let _ = writeln!(svg, r##"<line x1="{:.1}" y1="{py:.1}" x2="{:.1}" y2="{py:.1}" stroke="#e0e0e0"/>"##, MARGIN_LEFT, WIDTH - MARGIN_RIGHT);
+            let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#, MARGIN_LEFT - 6.0, py + 4.0, tick_label(y, y_step));
+        }
+        let x_step = x_ticks.get(1).map_or(1.0, |next| next - x_ticks[0]);
+        for &x in &x_ticks {
+            let px = to_px(x);
+            let _ = writeln!(svg, r##"<line x1="{px:.1}" y1="{top:.1}" x2="{px:.1}" y2="{bottom:.1}" stroke="#e0e0e0"/>"##);
+            let _ = writeln!(svg, r#"<text x="{px:.1}" y="{:.1}" text-anchor="middle">{}</text>"#, bottom + 16.0, tick_label(x, x_step));
+        }
+        if panel.zero_line {
+            let py = to_py(0.0);
+            let _ = writeln!(svg, r##"<line x1="{:.1}" y1="{py:.1}" x2="{:.1}" y2="{py:.1}" stroke="#444444"/>"##, MARGIN_LEFT, WIDTH - MARGIN_RIGHT);
+        }
+        let _ = writeln!(svg, r##"<rect x="{:.1}" y="{top:.1}" width="{plot_width:.1}" height="{:.1}" fill="none" stroke="#444444"/>"##, MARGIN_LEFT, panel.height);
+        let middle = (top + bottom) / 2.0;
+        let _ = writeln!(svg, r#"<text x="16" y="{middle:.1}" text-anchor="middle" transform="rotate(-90 16 {middle:.1})">{}</text>"#, panel.y_label);
+        let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{}</text>"#, MARGIN_LEFT + plot_width / 2.0, bottom + 36.0, x_label);
+
+        // Series, clipped to the plotting area
+        let _ = writeln!(svg, r#"<clipPath id="panel{index}"><rect x="{:.1}" y="{top:.1}" width="{plot_width:.1}" height="{:.1}"/></clipPath>"#, MARGIN_LEFT, panel.height);
+        let _ = writeln!(svg, r#"<g clip-path="url(#panel{index})">"#);
+        for series in &panel.series {
+            let points = series.points.iter().filter(|p| p.0.is_finite() && p.1.is_finite());
+            match series.style {
+                Style::Points => {
+                    let _ = writeln!(svg, r#"<g fill="{}" fill-opacity="0.35">"#, series.color);
+                    for &(x, y) in points {
+                        let _ = writeln!(svg, r#"<circle cx="{:.1}" cy="{:.1}" r="1.5"/>"#, to_px(x), to_py(y));
+                    }
+                    let _ = writeln!(svg, "</g>");
+                }
+                Style::Line | Style::Dashed => {
+                    let path: Vec<String> = points.map(|&(x, y)| format!("{:.1},{:.1}", to_px(x), to_py(y))).collect();
+                    let dash = if series.style == Style::Dashed { r#" stroke-dasharray="6 4""# } else { "" };
+                    let _ = writeln!(svg, r#"<polyline points="{}" fill="none" stroke="{}" stroke-width="2"{}/>"#, path.join(" "), series.color, dash);
+                }
+            }
+        }
+        let _ = writeln!(svg, "</g>");
+
+        // Legend in the top left corner of the panel
+        for (i, series) in panel.series.iter().enumerate() {
+            let y = top + 16.0 + i as f32 * 16.0;
+            let _ = writeln!(svg, r#"<rect x="{:.1}" y="{:.1}" width="12" height="4" fill="{}"/>"#, MARGIN_LEFT + 10.0, y - 6.0, series.color);
+            let _ = writeln!(svg, r#"<text x="{:.1}" y="{y:.1}">{}</text>"#, MARGIN_LEFT + 28.0, series.label);
+        }
+        top = bottom + PANEL_GAP;
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Writes the plot of a fit to an SVG file at `path`.
+pub fn write_svg<P: AsRef<Path>>(path: P, samples: &[Sample], stock: Option<&CalibrationTable>, fit: &Fit) -> io::Result<()> {
+    let x_label = format!("MAF ({})", fit.signal.unit());
+    fs::write(path, render_svg(&fit_panels(samples, stock, fit), &x_label))
+}
+
+/// Returns the trim map colour of a mean trim: blue when lean, white at zero, red when rich.
+fn trim_color(trim: f32) -> String {
+    let f = (trim / TRIM_LIMIT).clamp(-1.0, 1.0);
+    let (r, g, b) = if f >= 0.0 { (228.0, 87.0, 86.0) } else { (76.0, 120.0, 168.0) };
+    let mix = |c: f32| (255.0 + (c - 255.0) * f.abs()).round() as u8;
+    format!("#{:02x}{:02x}{:02x}", mix(r), mix(g), mix(b))
+}
+
+/// Renders `map` as an SVG heatmap: MAF signal across, RPM or load up, each cell coloured by
+/// its mean trim and faded by how few samples it holds. Hotspots are outlined.
+pub fn render_heatmap(map: &TrimMap) -> String {
+    let columns = map.populated_columns();
+    let width = MARGIN_LEFT + CELL_WIDTH * columns.len() as f32 + SCALE_WIDTH;
+    let height = MARGIN_TOP + CELL_HEIGHT * map.rows.count as f32 + MARGIN_BOTTOM;
+    let bottom = height - MARGIN_BOTTOM;
+    let to_px = |column: usize| MARGIN_LEFT + CELL_WIDTH * (column - columns.start) as f32;
+    let to_py = |row: usize| bottom - CELL_HEIGHT * row as f32;
+    let max_hits = map.cells.iter().flatten().map(|cell| cell.hits).max().unwrap_or(1).max(1);
+    let hotspots = map.hotspots();
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="sans-serif" font-size="11">"#, w = width, h = height);
+    let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+    let _ = writeln!(svg, r#"<text x="{:.1}" y="18">Mean trim (%) by MAF and {}; faded cells hold few samples</text>"#, MARGIN_LEFT, map.axis.label());
+    let plot_width = CELL_WIDTH * columns.len() as f32;
+    let _ = writeln!(svg, r##"<rect x="{:.1}" y="{:.1}" width="{plot_width:.1}" height="{:.1}" fill="#f4f4f4" stroke="#444444"/>"##, MARGIN_LEFT, MARGIN_TOP, bottom - MARGIN_TOP);
+
+    for column in columns.clone() {
+        for (row, cell) in map.cells[column].iter().enumerate() {
+            let Some(trim) = cell.mean() else { continue };
+            let (x, y) = (to_px(column), to_py(row + 1));
+            // Opacity grows with the log of the hits, so sparse cells stay visible
+            let opacity = 0.25 + 0.75 * ((1 + cell.hits) as f32).ln() / ((1 + max_hits) as f32).ln();
+            let _ = writeln!(
+                svg,
+                r#"<rect x="{x:.1}" y="{y:.1}" width="{CELL_WIDTH}" height="{CELL_HEIGHT}" fill="{}" fill-opacity="{opacity:.2}"><title>{} samples</title></rect>"#,
+                trim_color(trim), cell.hits
+            );
+            let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{:+.1}</text>"#, x + CELL_WIDTH / 2.0, y + CELL_HEIGHT / 2.0 + 4.0, trim);
+        }
+    }
+    for hotspot in &hotspots {
+        let _ = writeln!(
+            svg,
+            r##"<rect x="{:.1}" y="{:.1}" width="{CELL_WIDTH}" height="{CELL_HEIGHT}" fill="none" stroke="#222222" stroke-width="2"/>"##,
+            to_px(hotspot.column), to_py(hotspot.row + 1)
+        );
+    }
+
+    // Cell edges as ticks
+    for column in columns.start..=columns.end {
+        let x = map.columns.min + map.columns.width * column as f32;
+        let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">{}</text>"#, to_px(column), bottom + 16.0, tick_label(x, map.columns.width));
+    }
+    for row in 0..=map.rows.count {
+        let y = map.rows.min + map.rows.width * row as f32;
+        let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="end">{}</text>"#, MARGIN_LEFT - 6.0, to_py(row) + 4.0, tick_label(y, map.rows.width));
+    }
+    let middle = (MARGIN_TOP + bottom) / 2.0;
+    let _ = writeln!(svg, r#"<text x="16" y="{middle:.1}" text-anchor="middle" transform="rotate(-90 16 {middle:.1})">{}</text>"#, map.axis.label());
+    let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}" text-anchor="middle">MAF ({})</text>"#, MARGIN_LEFT + plot_width / 2.0, bottom + 36.0, map.signal.unit());
+
+    // Colour scale from -TRIM_LIMIT to +TRIM_LIMIT
+    let scale_x = MARGIN_LEFT + plot_width + 24.0;
+    let steps = 10;
+    let step_height = (bottom - MARGIN_TOP) / steps as f32;
+    for i in 0..steps {
+        let trim = TRIM_LIMIT - 2.0 * TRIM_LIMIT * (i as f32 + 0.5) / steps as f32;
+        let _ = writeln!(svg, r#"<rect x="{scale_x:.1}" y="{:.1}" width="14" height="{step_height:.1}" fill="{}"/>"#, MARGIN_TOP + step_height * i as f32, trim_color(trim));
+    }
+    for (y, label) in [(MARGIN_TOP, TRIM_LIMIT), (middle, 0.0), (bottom, -TRIM_LIMIT)] {
+        let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}">{:+.0}%</text>"#, scale_x + 20.0, y + 4.0, label);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Writes the heatmap of a trim map to an SVG file at `path`.
+pub fn write_heatmap<P: AsRef<Path>>(path: P, map: &TrimMap) -> io::Result<()> {
+    fs::write(path, render_heatmap(map))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit::FitReport;
+
+    #[test]
+    fn test_ticks() {
+        assert_eq!(ticks(0.3, 4.8), vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(ticks(-3.0, 7.0), vec![-4.0, -2.0, 0.0, 2.0, 4.0, 6.0, 8.0]);
+        assert_eq!(tick_label(2.5, 0.5), "2.5");
+        assert_eq!(tick_label(40.0, 20.0), "40");
+        assert_eq!(tick_label(0.75, 0.25), "0.75");
+    }
+
+    #[test]
+    fn test_fit_svg() {
+        let samples: Vec<Sample> = (0..50)
+            .map(|i| {
+                let x = 1.0 + i as f32 * 0.05;
+                Sample::point(x, 4.0 * x * x + 1.0)
+            })
+            .collect();
+        let report = FitReport { a: 4.0, n: 2.0, mse: 1.0, samples: 50, mean_trim: 0.0, mean_abs_trim: 0.0 };
+        let fit = Fit { signal: MafSignal::Voltage, x: vec![], y: vec![], y_fit: vec![], report };
+        let stock = CalibrationTable::new(vec![0.0, 2.0, 4.0], vec![0.0, 15.0, 60.0]).unwrap();
+
+        let panels = fit_panels(&samples, Some(&stock), &fit);
+        assert_eq!(panels[0].series.len(), 4);
+        assert!(panels[1].series[0].points.iter().all(|&(_, residual)| (residual - 1.0).abs() < 1e-3));
+
+        let svg = render_svg(&panels, "MAF (V)");
+        assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<circle").count(), 100);
+        assert!(svg.contains("Stock table") && svg.contains("Residual (g/s)") && svg.contains("MAF (V)"));
+    }
+}
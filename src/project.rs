@@ -0,0 +1,146 @@
+use std::{
+    fmt,
+    fs,
+    io,
+    path::Path,
+};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use crate::{fit::FitReport, table::CalibrationTable};
+
+/// An iterative calibration project: the stock table plus every revision generated from it.
+/// Revision 0 is the stock table itself. Each later revision is generated from logs driven on a
+/// revision before it, its base, and is numbered after the latest revision whatever its base.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Project {
+    pub stock: CalibrationTable,
+    #[serde(default)]
+    pub revisions: Vec<Revision>,
+}
+
+/// One generated table, the logs it was built from, and the report of its fit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    pub number: usize,
+    pub based_on: usize,
+    pub created: String,
+    pub logs: Vec<String>,
+    pub table: CalibrationTable,
+    pub report: FitReport,
+}
+
+impl Project {
+    /// Starts a project from a stock table, with no revisions yet.
+    pub fn new(stock: CalibrationTable) -> Self {
+        Project { stock, revisions: Vec::new() }
+    }
+
+    /// Reads a project from a JSON file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Writes the project to a JSON file, replacing any previous contents.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Returns the number of the most recent revision, or 0 when only the stock table exists.
+    pub fn latest(&self) -> usize {
+        self.revisions.last().map_or(0, |rev| rev.number)
+    }
+
+    /// Returns the table of revision `number`, where 0 is the stock table.
+    pub fn table(&self, number: usize) -> Option<&CalibrationTable> {
+        if number == 0 {
+            return Some(&self.stock);
+        }
+        self.revisions.iter().find(|rev| rev.number == number).map(|rev| &rev.table)
+    }
+
+    /// Records a new revision generated from logs driven on revision `based_on`, numbered after the latest.
+    pub fn push(&mut self, based_on: usize, logs: Vec<String>, table: CalibrationTable, report: FitReport) -> &Revision {
+        let revision = Revision {
+            number: self.latest() + 1,
+            based_on,
+            created: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            logs,
+            table,
+            report,
+        };
+        self.revisions.push(revision);
+        self.revisions.last().unwrap()
+    }
+
+    /// Returns a printable summary of how trims converged across revisions.
+    pub fn history(&self) -> History<'_> {
+        History(self)
+    }
+}
+
+/// Displays one row per revision with the trims that were measured on its base revision.
+pub struct History<'a>(&'a Project);
+
+impl fmt::Display for History<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Rev  Base  Logs  Samples  Mean trim  Mean |trim|  Change      MSE")?;
+        writeln!(f, "{:>3}  {:>4}  {:>4}  {:>7}  {:>9}  {:>11}  {:>6}  {:>7}", 0, "-", "-", "-", "-", "-", "-", "-")?;
+        let mut previous: Option<f32> = None;
+        for rev in &self.0.revisions {
+            let report = &rev.report;
+            let change = previous.map_or("-".to_string(), |prev| format!("{:+.2}", report.mean_abs_trim - prev));
+            writeln!(
+                f,
+                "{:>3}  {:>4}  {:>4}  {:>7}  {:>8.2}%  {:>10.2}%  {:>6}  {:>7.3}",
+                rev.number, rev.based_on, rev.logs.len(), report.samples,
+                report.mean_trim, report.mean_abs_trim, change, report.mse
+            )?;
+            previous = Some(report.mean_abs_trim);
+        }
+        writeln!(f, "Trims are those measured while driving on the base revision.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(mean_abs_trim: f32) -> FitReport {
+        FitReport { a: 1.0, n: 2.0, mse: 0.5, samples: 10, mean_trim: -mean_abs_trim, mean_abs_trim }
+    }
+
+    #[test]
+    fn test_revisions_chain_and_round_trip() {
+        let stock = CalibrationTable::new(vec![1.0, 2.0], vec![10.0, 20.0]).unwrap();
+        let mut project = Project::new(stock.clone());
+        assert_eq!(project.latest(), 0);
+        assert_eq!(project.table(0), Some(&stock));
+
+        let rev1 = CalibrationTable::new(vec![1.0, 2.0], vec![11.0, 21.0]).unwrap();
+        project.push(0, vec!["a.csv".to_string()], rev1.clone(), report(4.0));
+        project.push(1, vec!["b.csv".to_string()], stock.clone(), report(1.5));
+        assert_eq!(project.latest(), 2);
+        assert_eq!(project.table(1), Some(&rev1));
+        assert_eq!(project.revisions[1].based_on, 1);
+
+        let text = serde_json::to_string(&project).unwrap();
+        let loaded: Project = serde_json::from_str(&text).unwrap();
+        assert_eq!(loaded, project);
+        assert!(project.history().to_string().contains("-2.50"));
+    }
+
+    #[test]
+    fn test_revision_based_on_earlier_revision() {
+        let stock = CalibrationTable::new(vec![1.0, 2.0], vec![10.0, 20.0]).unwrap();
+        let mut project = Project::new(stock.clone());
+        for based_on in 0..3 {
+            project.push(based_on, Vec::new(), stock.clone(), report(1.0));
+        }
+        // Like `next --from 1` on a project at revision 3
+        let rev = project.push(1, vec!["c.csv".to_string()], stock, report(0.5));
+        assert_eq!((rev.number, rev.based_on), (4, 1));
+        assert_eq!(project.latest(), 4);
+        assert!(project.history().to_string().lines().any(|line| line.starts_with("  4     1")));
+    }
+}
@@ -0,0 +1,332 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io,
+    path::Path,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use crate::{
+    bins::{BinStats, Bins},
+    correction::CorrectionConfig,
+    delay::Delay,
+    fit::Fit,
+    plot::{fit_panels, render_heatmap, render_svg},
+    rules::RuleReport,
+    session::{Sample, SessionReport},
+    smooth::SmoothingReport,
+    table::CalibrationTable,
+    trim_map::TrimMap,
+};
+
+/// Styles of the report, inlined so the file needs nothing else to display.
+const STYLE: &str = "body{font-family:sans-serif;max-width:960px;margin:2em auto;color:#222}\
+table{border-collapse:collapse;margin:0.5em 0 1.5em}\
+th,td{border:1px solid #ccc;padding:2px 8px;text-align:right}\
+th{background:#f0f0f0}td.text{text-align:left}\
+tr.empty td{color:#999}.warning{color:#b00}";
+
+/// Path, size and modification time of a file a run read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputFile {
+    pub path: String,
+    pub bytes: u64,
+    pub modified: Option<String>,
+}
+
+impl InputFile {
+    /// Reads the metadata of the file at `path`.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified().ok()
+            .and_then(|time| OffsetDateTime::from(time).replace_nanosecond(0).ok())
+            .and_then(|time| time.format(&Rfc3339).ok());
+        Ok(InputFile { path: path.display().to_string(), bytes: metadata.len(), modified })
+    }
+}
+
+/// One row of the per-bin comparison: a table cell, or a signal bin when there is no stock table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BinRow {
+    pub x: f32,
+    pub stock: Option<f32>,
+    pub new: f32,
+    /// Samples nearer to this cell than to its neighbours.
+    pub hits: usize,
+}
+
+impl BinRow {
+    /// Returns the change from the stock value, in percent.
+    pub fn change(&self) -> Option<f32> {
+        self.stock.filter(|stock| stock.abs() > f32::EPSILON).map(|stock| (self.new / stock - 1.0) * 100.0)
+    }
+}
+
+/// Compares the new `table` with the stock table, cell by cell. Without a new table,
+/// the one `fit` produces from `stock` is used.
+/// Without a stock table, the fitted curve is listed at the centre of each signal bin with samples.
+///
+/// # Errors
+///
+/// Returns an error if `stock` is scaled on a different signal than the fit.
+pub fn bin_table(samples: &[Sample], stock: Option<&CalibrationTable>, table: Option<&CalibrationTable>, fit: &Fit) -> io::Result<Vec<BinRow>> {
+    let Some(stock) = stock else {
+        let bins = Bins::for_signal(fit.signal);
+        let mut stats = vec![BinStats::default(); bins.count];
+        for sample in samples {
+            if let Some(i) = bins.index(sample.x) {
+                stats[i].add(sample.y);
+            }
+        }
+        return Ok(stats.iter().enumerate()
+            .filter(|(_, stats)| stats.hits > 0)
+            .map(|(i, stats)| {
+                let (lo, hi) = bins.range(i);
+                let x = (lo + hi) / 2.0;
+                BinRow { x, stock: None, new: fit.eval(x), hits: stats.hits }
+            })
+            .collect());
+    };
+
+    let new = match table {
+        Some(table) => table.clone(),
+        None => fit.apply(stock)?,
+    };
+    Ok(stock.axis.iter().zip(&stock.values).zip(&new.values).zip(cell_hits(&stock.axis, samples))
+        .map(|(((&x, &stock), &new), hits)| BinRow { x, stock: Some(stock), new, hits })
+        .collect())
+}
+
+/// Counts the samples nearest to each cell of a table `axis`.
+/// Cells own the signal up to halfway to their neighbours.
+pub fn cell_hits(axis: &[f32], samples: &[Sample]) -> Vec<usize> {
+    let mut hits = vec![0; axis.len()];
+    for sample in samples {
+        let next = axis.partition_point(|&x| x < sample.x);
+        let cell = match next {
+            _ if axis.is_empty() => continue,
+            0 => 0,
+            n if n == axis.len() => n - 1,
+            n if sample.x - axis[n - 1] < axis[n] - sample.x => n - 1,
+            n => n,
+        };
+        hits[cell] += 1;
+    }
+    hits
+}
+
+/// Escapes text for use in HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Everything a single HTML report of one fit run shows.
+/// `session` and `rules` are `None` when the run did not read logs.
+pub struct HtmlReport<'a> {
+    pub samples: &'a [Sample],
+    pub fit: &'a Fit,
+    pub stock: Option<&'a CalibrationTable>,
+    /// The table written for the run, if it differs from the fit applied to `stock`.
+    pub table: Option<&'a CalibrationTable>,
+    pub smoothing: Option<&'a SmoothingReport>,
+    pub inputs: Vec<InputFile>,
+    pub session: Option<&'a SessionReport>,
+    pub rules: Option<&'a RuleReport>,
+    pub trim_map: Option<&'a TrimMap>,
+    pub correction: &'a CorrectionConfig,
+}
+
+impl HtmlReport<'_> {
+    /// Renders the report as one HTML document with inline styles and SVG, and no scripts.
+    pub fn render(&self) -> io::Result<String> {
+        let unit = self.fit.signal.unit();
+        let report = &self.fit.report;
+        let mut html = String::new();
+        let _ = writeln!(html, "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">");
+        let _ = writeln!(html, "<title>MAF calibration report</title>\n<style>{}</style>\n</head>\n<body>", STYLE);
+        let _ = writeln!(html, "<h1>MAF calibration report</h1>");
+        let _ = writeln!(html, "<p>Generated {}</p>", OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default());
+
+        let _ = writeln!(html, "<h2>Fit</h2>\n<table>");
+        let stats = [
+            ("Curve", format!("Y = {:.4} X<sup>{:.4}</sup>", report.a, report.n)),
+            ("MAF signal", format!("{:?} ({})", self.fit.signal, unit)),
+            ("Points fitted", report.samples.to_string()),
+            ("Mean squared error", format!("{:.4}", report.mse)),
+            ("Mean trim", format!("{:+.2}%", report.mean_trim)),
+            ("Mean absolute trim", format!("{:.2}%", report.mean_abs_trim)),
+        ];
+        for (name, value) in stats {
+            let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value);
+        }
+        let _ = writeln!(html, "</table>");
+
+        let _ = writeln!(html, "<h2>Plots</h2>");
+        html.push_str(&render_svg(&fit_panels(self.samples, self.stock, self.fit), &format!("MAF ({})", unit)));
+
+        let _ = writeln!(html, "<h2>Table</h2>\n<table>");
+        let _ = writeln!(html, "<tr><th>MAF ({})</th><th>Stock (g/s)</th><th>New (g/s)</th><th>Change</th><th>Hits</th></tr>", unit);
+        for row in bin_table(self.samples, self.stock, self.table, self.fit)? {
+            let optional = |value: Option<f32>, format: fn(f32) -> String| value.map_or_else(|| "-".to_string(), format);
+            let _ = writeln!(
+                html,
+                "<tr{}><td>{:.3}</td><td>{}</td><td>{:.2}</td><td>{}</td><td>{}</td></tr>",
+                if row.hits == 0 { " class=\"empty\"" } else { "" },
+                row.x,
+                optional(row.stock, |v| format!("{:.2}", v)),
+                row.new,
+                optional(row.change(), |v| format!("{:+.1}%", v)),
+                row.hits,
+            );
+        }
+        let _ = writeln!(html, "</table>");
+        if let Some(smoothing) = self.smoothing {
+            let _ = writeln!(html, "<h3>Smoothing</h3>\n<pre>{}</pre>", escape(&smoothing.to_string()));
+        }
+
+        if let Some(map) = self.trim_map {
+            let _ = writeln!(html, "<h2>Trims by MAF and {}</h2>", map.axis.label());
+            html.push_str(&render_heatmap(map));
+            let _ = writeln!(html, "<pre>{}</pre>", escape(&map.to_string()));
+        }
+
+        self.render_filters(&mut html);
+        self.render_inputs(&mut html);
+        let _ = writeln!(html, "</body>\n</html>");
+        Ok(html)
+    }
+
+    /// Lists the correction settings and what the rules and blacklist removed.
+    fn render_filters(&self, html: &mut String) {
+        let correction = self.correction;
+        let _ = writeln!(html, "<h2>Filters</h2>\n<table>");
+        let delay = match &correction.delay {
+            None => "none".to_string(),
+            Some(Delay::Fixed(seconds)) => format!("{:.2} s", seconds),
+            Some(Delay::Rpm(points)) => points.iter().map(|(rpm, s)| format!("{} rpm: {:.2} s", rpm, s)).collect::<Vec<_>>().join(", "),
+            Some(Delay::Auto) => "estimated per log".to_string(),
+        };
+        let open_loop = correction.open_loop.as_ref()
+            .map_or_else(|| "off".to_string(), |open_loop| format!("from {} {}", open_loop.boundary, self.fit.signal.unit()));
+        let _ = writeln!(html, "<tr><th>Transport delay</th><td class=\"text\">{}</td></tr>", escape(&delay));
+        let _ = writeln!(html, "<tr><th>Open-loop correction</th><td class=\"text\">{}</td></tr>", open_loop);
+        let blacklist = &correction.blacklist;
+        for column in &blacklist.columns {
+            let _ = writeln!(html, "<tr><th>Blacklisted column</th><td class=\"text\">{}</td></tr>", escape(column));
+        }
+        for range in &blacklist.times {
+            let _ = writeln!(html, "<tr><th>Blacklisted time</th><td class=\"text\">{} {:.1}-{:.1} s</td></tr>", escape(&range.log), range.from, range.to);
+        }
+        for (from, to) in &blacklist.signals {
+            let _ = writeln!(html, "<tr><th>Blacklisted signal</th><td class=\"text\">{}-{} {}</td></tr>", from, to, self.fit.signal.unit());
+        }
+        let _ = writeln!(html, "</table>");
+
+        if let Some(rules) = self.rules.filter(|rules| !rules.rules.is_empty()) {
+            let _ = writeln!(html, "<table>\n<tr><th>Exclusion rule</th><th>Rows dropped</th></tr>");
+            for (rule, drops) in rules.rules.iter().zip(&rules.drops) {
+                let _ = writeln!(html, "<tr><td class=\"text\"><code>{}</code></td><td>{}</td></tr>", escape(rule), drops);
+            }
+            let _ = writeln!(html, "</table>");
+        }
+    }
+
+    /// Lists the input files with their coverage, and any disagreement between them.
+    fn render_inputs(&self, html: &mut String) {
+        let unit = self.fit.signal.unit();
+        let _ = writeln!(html, "<h2>Inputs</h2>\n<table>");
+        let _ = writeln!(html, "<tr><th>File</th><th>Size</th><th>Modified</th><th>Rows</th><th>Samples</th><th>Range ({})</th><th>Logged with</th></tr>", unit);
+        let coverage = self.session.map(|session| session.coverage.as_slice()).unwrap_or_default();
+        for (i, input) in self.inputs.iter().enumerate() {
+            let _ = write!(
+                html,
+                "<tr><td class=\"text\">{}</td><td>{:.1} kB</td><td class=\"text\">{}</td>",
+                escape(&input.path), input.bytes as f32 / 1024.0, input.modified.as_deref().unwrap_or("-")
+            );
+            match coverage.get(i) {
+                Some(cov) => {
+                    let range = if cov.samples > 0 { format!("{:.2}-{:.2}", cov.min_x, cov.max_x) } else { "-".to_string() };
+                    let device = cov.device.as_ref().map_or_else(|| "-".to_string(), |device| escape(&device.to_string()));
+                    let _ = writeln!(html, "<td>{}</td><td>{}</td><td>{}</td><td class=\"text\">{}</td></tr>", cov.rows, cov.samples, range, device);
+                }
+                None => {
+                    let _ = writeln!(html, "<td>-</td><td>-</td><td>-</td><td class=\"text\">-</td></tr>");
+                }
+            }
+        }
+        let _ = writeln!(html, "</table>");
+        if let Some(session) = self.session {
+            for d in &session.disagreements {
+                let _ = writeln!(
+                    html,
+                    "<p class=\"warning\">{} and {} disagree by up to {:.1}% between {:.2} {unit} and {:.2} {unit}</p>",
+                    escape(&session.coverage[d.sources.0].name), escape(&session.coverage[d.sources.1].name), d.worst_pct, d.from, d.to
+                );
+            }
+            let _ = writeln!(html, "<h3>Channels</h3>\n<table>");
+            for cov in &session.coverage {
+                let _ = writeln!(html, "<tr><td class=\"text\">{}</td><td class=\"text\">{}</td></tr>", escape(&cov.name), escape(&cov.channels.join(", ")));
+            }
+            let _ = writeln!(html, "</table>");
+        }
+    }
+
+    /// Writes the report to `path`.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.render()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::MafSignal, fit::FitReport};
+
+    fn samples() -> Vec<Sample> {
+        [1.0, 1.1, 1.6, 2.9].iter().map(|&x| Sample::point(x, 10.0 * x)).collect()
+    }
+
+    fn fit() -> Fit {
+        let report = FitReport { a: 10.0, n: 1.0, mse: 0.0, samples: 4, mean_trim: 0.0, mean_abs_trim: 0.0 };
+        Fit { signal: MafSignal::Voltage, x: vec![1.0, 2.9], y: vec![], y_fit: vec![], report }
+    }
+
+    fn stock() -> CalibrationTable {
+        CalibrationTable::new(vec![1.0, 2.0, 3.0, 4.0], vec![8.0, 20.0, 25.0, 30.0]).unwrap()
+    }
+
+    #[test]
+    fn test_bin_table() {
+        let rows = bin_table(&samples(), Some(&stock()), None, &fit()).unwrap();
+        assert_eq!(rows.iter().map(|row| row.hits).collect::<Vec<_>>(), vec![2, 1, 1, 0]);
+        assert_eq!(rows[0].change(), Some(25.0));
+        // Outside the logged range the stock value is kept
+        assert_eq!((rows[3].new, rows[3].change()), (30.0, Some(0.0)));
+    }
+
+    #[test]
+    fn test_bin_table_without_stock() {
+        assert_eq!(bin_table(&samples(), None, None, &fit()).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_report_is_self_contained() {
+        let (samples, fit, stock) = (samples(), fit(), stock());
+        let html = HtmlReport {
+            samples: &samples,
+            fit: &fit,
+            stock: Some(&stock),
+            table: None,
+            smoothing: None,
+            inputs: vec![InputFile { path: "a<b>.csv".to_string(), bytes: 2048, modified: None }],
+            session: None,
+            rules: None,
+            trim_map: None,
+            correction: &CorrectionConfig::default(),
+        }
+        .render()
+        .unwrap();
+        assert!(html.contains("<svg") && html.contains("+25.0%") && html.contains("a&lt;b&gt;.csv"));
+        assert!(!html.contains("<script") && !html.contains("src=") && !html.contains("<link"));
+    }
+}
@@ -0,0 +1,165 @@
+use std::io;
+use crate::data::LogData;
+
+/// How the rows of a log are combined onto the common time base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResampleMethod {
+    /// Averages the rows in each interval, to decimate a fast log without keeping its noise.
+    Mean,
+    /// Interpolates linearly at each instant, to bring a slow log up to a faster rate.
+    Linear,
+}
+
+/// The time base every log of a session is brought onto before merging: `rate` rows per second.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resampling {
+    pub rate: f32,
+    pub method: ResampleMethod,
+}
+
+impl Resampling {
+    /// Parses `RATE`, `RATE:mean` or `RATE:linear`, with the rate in Hz. Averaging is the default.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (rate, method) = text.split_once(':').unwrap_or((text, "mean"));
+        let rate = rate.trim().parse::<f32>().ok()
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+            .ok_or_else(|| format!("invalid rate '{}'", rate))?;
+        let method = match method.trim() {
+            "mean" => ResampleMethod::Mean,
+            "linear" => ResampleMethod::Linear,
+            other => return Err(format!("unknown method '{}', expected mean or linear", other)),
+        };
+        Ok(Resampling { rate, method })
+    }
+}
+
+/// How much each log of a session counts towards the fit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Weighting {
+    /// Every sample counts the same, so a log counts by how many rows it has.
+    #[default]
+    Rows,
+    /// Every log counts by the time it covers, however fast it was sampled.
+    Time,
+}
+
+impl Weighting {
+    /// Parses `rows` or `time`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text {
+            "rows" => Ok(Weighting::Rows),
+            "time" => Ok(Weighting::Time),
+            _ => Err(format!("expected rows or time, found '{}'", text)),
+        }
+    }
+}
+
+/// Brings `data` onto the time base of `resampling`.
+///
+/// # Errors
+///
+/// Returns an error if the log has no time column or no rows.
+pub fn resample(data: &LogData, resampling: &Resampling) -> io::Result<LogData> {
+    let resampled = match resampling.method {
+        ResampleMethod::Mean => data.average(resampling.rate),
+        ResampleMethod::Linear => data.resample(resampling.rate),
+    };
+    resampled.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Resampling needs a Time column"))
+}
+
+/// Returns the time (seconds) covered by the rows at the `kept` times of a log timed by `time`.
+/// Each row covers the interval up to the next later row, the last ones the interval before them,
+/// and rows logged at the same time share its interval.
+pub fn covered_time(time: &[f32], kept: impl IntoIterator<Item = f32>) -> f32 {
+    kept.into_iter()
+        .map(|t| {
+            let start = time.partition_point(|&p| p < t);
+            let end = time.partition_point(|&p| p <= t);
+            let interval = match (time.get(end), start.checked_sub(1)) {
+                _ if start == end => 0.0,
+                (Some(&next), _) => next - t,
+                (None, Some(previous)) => t - time[previous],
+                (None, None) => 0.0,
+            };
+            interval / (end - start).max(1) as f32
+        })
+        .sum()
+}
+
+/// Returns the weight of one sample of each source, so that each source adds up to its logged
+/// duration (seconds) rather than its count of samples. Weights average 1 over every sample.
+pub fn time_weights(durations: &[f32], counts: &[usize]) -> Vec<f32> {
+    let total_duration: f32 = durations.iter().sum();
+    let total_count: usize = counts.iter().sum();
+    if total_duration <= 0.0 || total_count == 0 {
+        return vec![1.0; counts.len()];
+    }
+    let mean = total_duration / total_count as f32;
+    durations.iter().zip(counts)
+        .map(|(&duration, &count)| if count > 0 { duration / count as f32 / mean } else { 0.0 })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::LogField,
+        testing::{log, row},
+    };
+
+    /// A 4 ms log over 0.2 s, with no short term trim in row 3.
+    fn fast() -> LogData {
+        log((0..50).map(|i| {
+            let stft = if i == 3 { f32::NAN } else { i as f32 };
+            row(2.0).with(LogField::TIME, i as f32 * 0.004).with(LogField::STFT, stft)
+        }))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Resampling::parse("10"), Ok(Resampling { rate: 10.0, method: ResampleMethod::Mean }));
+        assert_eq!(Resampling::parse("2.5:linear").unwrap().method, ResampleMethod::Linear);
+        assert!(Resampling::parse("0").is_err());
+        assert!(Resampling::parse("10:cubic").is_err());
+        assert_eq!(Weighting::parse("time"), Ok(Weighting::Time));
+    }
+
+    #[test]
+    fn test_average() {
+        let averaged = resample(&fast(), &Resampling::parse("10").unwrap()).unwrap();
+        assert_eq!(averaged.len(), 2);
+        let stft = averaged.get(&LogField::STFT).unwrap();
+        // Rows 0-24 fall in the first 100 ms, without the NaN in row 3
+        assert!((stft[0] - (300.0 - 3.0) / 24.0).abs() < 1e-4);
+        assert!((stft[1] - 37.0).abs() < 1e-4);
+        assert!((averaged.time().unwrap()[0] - 0.048).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_interpolate() {
+        assert_eq!(resample(&fast(), &Resampling::parse("50:linear").unwrap()).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_resample_needs_time() {
+        assert!(resample(&LogData::default(), &Resampling::parse("10").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_time_weights() {
+        // 0.2 s over 50 samples against 20 s over 50 samples
+        let weights = time_weights(&[0.2, 20.0], &[50, 50]);
+        assert!((weights[0] / weights[1] - 0.01).abs() < 1e-6);
+        assert!((weights[0] * 50.0 + weights[1] * 50.0 - 100.0).abs() < 1e-3);
+        assert_eq!(time_weights(&[0.0, 0.0], &[3, 4]), vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_covered_time() {
+        let time = [0.0, 0.1, 0.1, 0.3, 0.4];
+        assert!((covered_time(&time, time) - 0.5).abs() < 1e-6);
+        // A gap left by dropped rows is not covered, and one of two rows at 0.1 s covers half
+        assert!((covered_time(&time, [0.0, 0.1, 0.4]) - 0.3).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    fs,
+    io,
+    path::Path,
+};
+use crate::{
+    data::LogData,
+    table::invalid,
+};
+
+/// The rule set used when no rules file is given, see `rules/default.rules`.
+const DEFAULT_RULES: &str = include_str!("../rules/default.rules");
+/// Prefix of a column naming the rate of change per second of a channel, e.g. `d/dt RPM`.
+const DERIVATIVE: &str = "d/dt ";
+
+/// A comparison between a log value and a rule's constant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl Op {
+    /// Operators by their text, two-character ones first so `>=` is not read as `>`.
+    const SYMBOLS: [(&'static str, Op); 6] = [
+        (">=", Op::Ge),
+        ("<=", Op::Le),
+        ("==", Op::Eq),
+        ("!=", Op::Ne),
+        (">", Op::Gt),
+        ("<", Op::Lt),
+    ];
+
+    /// Compares `value` against `limit`. A NaN value never matches.
+    fn test(self, value: f32, limit: f32) -> bool {
+        match self {
+            Op::Gt => value > limit,
+            Op::Ge => value >= limit,
+            Op::Lt => value < limit,
+            Op::Le => value <= limit,
+            Op::Eq => value == limit,
+            Op::Ne => !value.is_nan() && value != limit,
+        }
+    }
+}
+
+/// One exclusion rule, e.g. `"Knock Retard" > 0 => drop`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub text: String,
+    /// Channel name or well-known field alias, see `LogData::channel`.
+    pub column: String,
+    pub op: Op,
+    pub value: f32,
+}
+
+impl Rule {
+    /// Parses a rule. The column may be quoted and names any channel of a log.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let (condition, action) = line.split_once("=>").ok_or("expected '=> drop'")?;
+        if action.trim() != "drop" {
+            return Err(format!("unknown action '{}'", action.trim()));
+        }
+
+        let condition = condition.trim();
+        let (column, rest) = match condition.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').ok_or("unterminated column name")?,
+            None => {
+                let at = condition.find(['>', '<', '=', '!']).ok_or("expected a comparison")?;
+                condition.split_at(at)
+            }
+        };
+        let column = column.trim();
+        if column.is_empty() {
+            return Err("missing column name".to_string());
+        }
+
+        let rest = rest.trim();
+        let &(symbol, op) = Op::SYMBOLS.iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+            .ok_or("expected one of > >= < <= == !=")?;
+        let value = rest[symbol.len()..].trim();
+        let value = value.parse().map_err(|_| format!("invalid number '{}'", value))?;
+        Ok(Rule { text: line.trim().to_string(), column: column.to_string(), op, value })
+    }
+
+    /// Returns the values the rule tests in `data`: a channel, or the rate of change of one
+    /// for a `d/dt` column.
+    fn values<'a>(&self, data: &'a LogData) -> Option<Cow<'a, [f32]>> {
+        match self.column.strip_prefix(DERIVATIVE) {
+            Some(name) => data.derivative(name).map(Cow::Owned),
+            None => data.channel(&self.column).map(|channel| Cow::Borrowed(&channel.values[..])),
+        }
+    }
+
+    /// Returns `true` if row `i` of `column`, the rule's values in a log, matches.
+    /// A rule never matches a log without its column.
+    fn matches(&self, column: Option<&[f32]>, i: usize) -> bool {
+        column.is_some_and(|values| self.op.test(values[i], self.value))
+    }
+}
+
+/// An ordered list of exclusion rules.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+}
+
+impl RuleSet {
+    /// Returns the default rule set shipped with the program.
+    pub fn default_rules() -> Self {
+        Self::parse(DEFAULT_RULES).expect("default rules are valid")
+    }
+
+    /// Loads a rules file, see `parse` for the format.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .and_then(|text| Self::parse(&text))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses one rule per line. Blank lines and lines starting with `#` are ignored.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let rules = text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+            .map(|(number, line)| Rule::parse(line).map_err(|e| invalid(format!("Line {}: {}", number + 1, e))))
+            .collect::<io::Result<Vec<Rule>>>()?;
+        Ok(RuleSet { rules })
+    }
+
+    /// Returns `true` if a rule tests a rate of change, which needs the rows either side of each row.
+    pub fn uses_derivatives(&self) -> bool {
+        self.rules.iter().any(|rule| rule.column.starts_with(DERIVATIVE))
+    }
+
+    /// Removes every row matching a rule from `data` and returns how many rows each rule dropped.
+    /// A row matching several rules is counted against the first.
+    pub fn apply(&self, data: &mut LogData) -> Vec<usize> {
+        let (keep, drops) = self.keep(data);
+        data.retain_rows(&keep);
+        drops
+    }
+
+    /// Returns which rows of `data` match no rule, and how many rows each rule matches first.
+    pub fn keep(&self, data: &LogData) -> (Vec<bool>, Vec<usize>) {
+        let mut drops = vec![0; self.rules.len()];
+        // Look each column up once rather than per row
+        let columns: Vec<Option<Cow<[f32]>>> = self.rules.iter().map(|rule| rule.values(data)).collect();
+        let keep: Vec<bool> = (0..data.len())
+            .map(|i| match self.rules.iter().zip(&columns).position(|(rule, column)| rule.matches(column.as_deref(), i)) {
+                Some(rule) => {
+                    drops[rule] += 1;
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        (keep, drops)
+    }
+}
+
+/// Rows dropped by each rule over a whole session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleReport {
+    pub rules: Vec<String>,
+    pub drops: Vec<usize>,
+}
+
+impl RuleReport {
+    /// Starts a report with no drops for every rule in `rules`.
+    pub fn new(rules: &RuleSet) -> Self {
+        RuleReport { rules: rules.rules.iter().map(|rule| rule.text.clone()).collect(), drops: vec![0; rules.rules.len()] }
+    }
+
+    /// Adds the drops of one log.
+    pub fn add(&mut self, drops: &[usize]) {
+        for (total, drops) in self.drops.iter_mut().zip(drops) {
+            *total += drops;
+        }
+    }
+}
+
+impl fmt::Display for RuleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        writeln!(f, "Rows dropped by exclusion rules:")?;
+        for (rule, drops) in self.rules.iter().zip(&self.drops) {
+            writeln!(f, "  {:>6}  {}", drops, rule)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{Channel, LogField},
+        testing::{log, row},
+    };
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(RuleSet::default_rules().rules[0], Rule {
+            text: "\"Knock Retard\" > 0 => drop".to_string(),
+            column: "Knock Retard".to_string(),
+            op: Op::Gt,
+            value: 0.0,
+        });
+        assert_eq!(Rule::parse("Inj. Duty Cycle >= 90 => drop").unwrap().op, Op::Ge);
+        assert_eq!(Rule::parse("\"d/dt Boost\" > 30 => drop").unwrap().column, "d/dt Boost");
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_rules() {
+        assert!(RuleSet::parse("\"Knock Retard\" > 0 => keep").is_err());
+        assert!(RuleSet::parse("\"\" > 0 => drop").is_err());
+    }
+
+    #[test]
+    fn test_default_rules_drop_and_count() {
+        let rows = [(0.0, 20.0), (1.4, 20.0), (0.0, 0.0), (1.0, 95.0), (f32::NAN, 30.0)];
+        let mut data = log(rows.map(|(knock, duty)| row(2.0).with(LogField::KNOCK, knock).with(LogField::IDC, duty)));
+        assert_eq!(RuleSet::default_rules().apply(&mut data), vec![2, 1, 0]);
+        assert_eq!(data.get(&LogField::IDC).unwrap(), &vec![20.0, 30.0]);
+    }
+
+    #[test]
+    fn test_rules_on_any_channel() {
+        let mut data = LogData::empty();
+        data.add_channel(Channel::from_header("Time (sec)"), Some(LogField::TIME));
+        data.add_channel(Channel::from_header("Boost (psi)"), None);
+        data.add_channel(Channel::from_header("Inj. Duty Cycle (%)"), Some(LogField::IDC));
+        for row in [[0.0, 5.0, 20.0], [0.1, 5.0, 20.0], [0.2, 12.0, 20.0], [0.3, f32::NAN, 0.0], [0.4, 6.0, 20.0]] {
+            data.push_values(&row);
+        }
+        // A channel the log does not have drops nothing
+        let rules = RuleSet::parse(
+            "Boost > 10 => drop\n\"Fuel Level\" > 0 => drop\n\"Inj. Duty Cycle\" <= 0 => drop\n\"d/dt Boost\" > 30 => drop",
+        ).unwrap();
+        assert_eq!(rules.apply(&mut data), vec![1, 0, 1, 1]);
+        assert_eq!(data.channel("boost").unwrap().values, vec![5.0, 6.0]);
+    }
+}
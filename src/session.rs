@@ -0,0 +1,463 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+    io,
+    path::Path,
+};
+use crate::{
+    blacklist::Blacklist,
+    bins::{BinStats, Bins},
+    correction::{correct, CorrectionConfig, Method},
+    data::{Channel, DeviceInfo, F32, LogData, MafSignal},
+    delay::align,
+    log_reader::read_log,
+    resample::{covered_time, resample, time_weights, Weighting},
+    rules::RuleReport,
+};
+
+/// Minimum hits a source needs in a bin before it takes part in a disagreement check.
+const MIN_BIN_HITS: usize = 5;
+/// Relative difference (in %) between two sources' bin means that triggers a warning.
+const DISAGREEMENT_PCT: f32 = 10.0;
+
+/// One log file loaded into a session.
+pub struct LogSource {
+    pub name: String,
+    pub data: LogData,
+}
+
+/// A corrected sample tagged with the index of the `LogSource` it came from.
+/// `trim` is the correction (%) that was applied to the measured airflow to get `y`,
+/// taken from the fuel trims or the wideband as recorded by `method`.
+/// `iat` is the intake air temperature of the row, and `rpm` and `load` its engine speed and
+/// calculated load, each NaN if the log has no such column.
+/// `weight` is how much the sample counts towards the fit, 1 unless logs are weighted by time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub x: f32,
+    pub y: f32,
+    pub trim: f32,
+    pub method: Method,
+    pub source: usize,
+    pub iat: f32,
+    pub rpm: f32,
+    pub load: f32,
+    pub weight: f32,
+}
+
+impl Sample {
+    /// Returns an untrimmed closed-loop sample of the first source with nothing else known about
+    /// its row, such as a cell of a table.
+    pub fn point(x: f32, y: f32) -> Self {
+        Sample { x, y, trim: 0.0, method: Method::Trims, source: 0, iat: f32::NAN, rpm: f32::NAN, load: f32::NAN, weight: 1.0 }
+    }
+}
+
+/// A calibration session made of one or more logs, fitted as a single union of samples.
+/// Every log in a session carries the same MAF signal.
+pub struct Session {
+    pub sources: Vec<LogSource>,
+}
+
+impl Session {
+    /// Loads every log in `paths`. Each file may use its own header layout.
+    /// Blacklisted columns are not parsed and rows in blacklisted time ranges are dropped.
+    pub fn load<P: AsRef<Path>>(paths: &[P], blacklist: &Blacklist) -> io::Result<Self> {
+        let mut sources: Vec<LogSource> = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            let mut data = read_log(path, blacklist)?;
+            let dropped = blacklist.drop_times(path, &mut data)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            if dropped > 0 {
+                println!("{}: {} rows in blacklisted time ranges dropped", path.display(), dropped);
+            }
+            if data.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} contains no complete rows", path.display()),
+                ));
+            }
+            if let Some(first) = sources.first() {
+                if first.data.signal != data.signal {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} logs MAF {} but {} logs MAF {}",
+                            first.name, first.data.signal.unit(), path.display(), data.signal.unit()
+                        ),
+                    ));
+                }
+            }
+            sources.push(LogSource { name: path.display().to_string(), data });
+        }
+        Ok(Session { sources })
+    }
+
+    /// Returns the MAF signal shared by every log in the session.
+    pub fn signal(&self) -> MafSignal {
+        self.sources.first().map_or_else(MafSignal::default, |log| log.data.signal)
+    }
+
+    /// Returns the corrected samples of every source, tagged with their source index, and the rows
+    /// dropped by each exclusion rule. Each source is aligned for transport delay if `config` asks
+    /// for it, then the rules drop rows as logged, and only then is what is left resampled, so a
+    /// dropped row is never blended into the rows kept. Samples in blacklisted MAF signal ranges
+    /// are left out.
+    ///
+    /// Weighting by time gives the distinct points of each source a total weight in proportion to
+    /// the logged time the rules kept, less the share of rows the correction or blacklist drop.
+    pub fn samples(&self, config: &CorrectionConfig) -> io::Result<(Vec<Sample>, RuleReport)> {
+        let mut samples = Vec::new();
+        let mut durations = Vec::with_capacity(self.sources.len());
+        let mut dropped = RuleReport::new(&config.rules);
+        for (source, log) in self.sources.iter().enumerate() {
+            let with_name = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", log.name, e));
+            let mut data = Cow::Borrowed(&log.data);
+            if let Some(delay) = &config.delay {
+                let (aligned, lag) = align(&data, delay).map_err(with_name)?;
+                if let Some(lag) = lag {
+                    println!("{}: trims and AFR shifted by {:.2} s", log.name, lag);
+                }
+                data = Cow::Owned(aligned);
+            }
+            // The rows as logged, before the rules drop any
+            let logged = match config.weighting {
+                Weighting::Time => Some(data.time().filter(|time| !time.is_empty()).map(<[f32]>::to_vec).ok_or_else(|| {
+                    with_name(io::Error::new(io::ErrorKind::InvalidInput, "Weighting by time needs a Time column"))
+                })?),
+                Weighting::Rows => None,
+            };
+            if !config.rules.rules.is_empty() {
+                dropped.add(&config.rules.apply(data.to_mut()));
+            }
+            let covered = logged.map(|logged| covered_time(&logged, data.time().unwrap_or_default().iter().copied()));
+            // A log the rules emptied has nothing left to resample
+            if let Some(resampling) = config.resample.as_ref().filter(|_| !data.is_empty()) {
+                let rows = data.len();
+                data = Cow::Owned(resample(&data, resampling).map_err(with_name)?);
+                println!("{}: resampled from {} to {} rows at {} Hz", log.name, rows, data.len(), resampling.rate);
+            }
+            let mut corrected = correct(&data, source, config).map_err(with_name)?;
+            corrected.retain(|sample| !config.blacklist.excludes_signal(sample.x));
+            if let Some(covered) = covered {
+                durations.push(covered * corrected.len() as f32 / data.len().max(1) as f32);
+            }
+            samples.extend(corrected);
+        }
+
+        if config.weighting == Weighting::Time {
+            // Count the points the fit sees, one per source however often it was logged
+            let mut counts = vec![0; self.sources.len()];
+            let mut seen = HashSet::new();
+            for sample in &samples {
+                if seen.insert((sample.source, F32(sample.x), F32(sample.y))) {
+                    counts[sample.source] += 1;
+                }
+            }
+            let weights = time_weights(&durations, &counts);
+            for sample in &mut samples {
+                sample.weight = weights[sample.source];
+            }
+        }
+        Ok((samples, dropped))
+    }
+
+    /// Builds the per-source coverage and cross-source disagreement report for `samples`.
+    pub fn report(&self, samples: &[Sample]) -> SessionReport {
+        let bins = Bins::for_signal(self.signal());
+        let mut per_source = vec![vec![BinStats::default(); bins.count]; self.sources.len()];
+        let mut coverage: Vec<SourceCoverage> = self.sources.iter()
+            .map(|log| SourceCoverage {
+                name: log.name.clone(),
+                rows: log.data.len(),
+                samples: 0,
+                wideband: 0,
+                wideband_error: 0.0,
+                min_x: f32::MAX,
+                max_x: f32::MIN,
+                bins_hit: 0,
+                device: log.data.device.clone(),
+                channels: log.data.channels().iter().map(Channel::header).collect(),
+                weight: 1.0,
+            })
+            .collect();
+
+        for sample in samples {
+            let cov = &mut coverage[sample.source];
+            cov.samples += 1;
+            cov.weight = sample.weight;
+            if sample.method == Method::Wideband {
+                cov.wideband += 1;
+                cov.wideband_error += sample.trim;
+            }
+            cov.min_x = cov.min_x.min(sample.x);
+            cov.max_x = cov.max_x.max(sample.x);
+            if let Some(i) = bins.index(sample.x) {
+                per_source[sample.source][i].add(sample.y);
+            }
+        }
+        for (cov, stats) in coverage.iter_mut().zip(per_source.iter()) {
+            cov.bins_hit = stats.iter().filter(|s| s.hits > 0).count();
+            cov.wideband_error /= cov.wideband.max(1) as f32;
+        }
+
+        SessionReport { signal: self.signal(), coverage, disagreements: disagreements(&bins, &per_source) }
+    }
+}
+
+/// Deduplicates the X and Y values of `samples` in preparation for curve fitting.
+/// Returns the weight of each point alongside: a point counts once for every source that logged it,
+/// with that source's weight.
+pub fn deduplicate(samples: &[Sample]) -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+    let mut points = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut deduplicated_x = Vec::new();
+    let mut deduplicated_y = Vec::new();
+    let mut weights = Vec::new();
+    for sample in samples {
+        let point = (F32(sample.x), F32(sample.y));
+        if !seen.insert((sample.source, point)) {
+            continue;
+        }
+        match points.entry(point) {
+            Entry::Occupied(entry) => weights[*entry.get()] += sample.weight,
+            Entry::Vacant(entry) => {
+                entry.insert(weights.len());
+                deduplicated_x.push(sample.x);
+                deduplicated_y.push(sample.y);
+                weights.push(sample.weight);
+            }
+        }
+    }
+    (deduplicated_x, deduplicated_y, weights)
+}
+
+/// Finds signal regions where two sources both have enough hits but disagree on the corrected airflow.
+/// Adjacent bins flagged for the same pair of sources are merged into one region.
+fn disagreements(bins: &Bins, per_source: &[Vec<BinStats>]) -> Vec<Disagreement> {
+    let mut found: Vec<Disagreement> = Vec::new();
+    for a in 0..per_source.len() {
+        for b in a + 1..per_source.len() {
+            let mut open: Option<Disagreement> = None;
+            for (i, (sa, sb)) in per_source[a].iter().zip(&per_source[b]).enumerate() {
+                let diff = match (sa.mean(), sb.mean()) {
+                    (Some(ma), Some(mb)) if sa.hits >= MIN_BIN_HITS && sb.hits >= MIN_BIN_HITS => {
+                        let mid = (ma.abs() + mb.abs()) / 2.0;
+                        (mid > f32::EPSILON).then(|| (ma - mb).abs() / mid * 100.0)
+                    }
+                    _ => None,
+                };
+                match diff {
+                    Some(pct) if pct > DISAGREEMENT_PCT => {
+                        let (lo, hi) = bins.range(i);
+                        match open.as_mut() {
+                            Some(region) => {
+                                region.to = hi;
+                                region.worst_pct = region.worst_pct.max(pct);
+                            }
+                            None => open = Some(Disagreement { from: lo, to: hi, sources: (a, b), worst_pct: pct }),
+                        }
+                    }
+                    _ => found.extend(open.take()),
+                }
+            }
+            found.extend(open);
+        }
+    }
+    found
+}
+
+/// Sample coverage of a single source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceCoverage {
+    pub name: String,
+    pub rows: usize,
+    pub samples: usize,
+    pub wideband: usize,
+    /// Mean measured vs commanded AFR error (%) over the wideband samples.
+    pub wideband_error: f32,
+    pub min_x: f32,
+    pub max_x: f32,
+    pub bins_hit: usize,
+    pub device: Option<DeviceInfo>,
+    /// Header of every channel the log was loaded with.
+    pub channels: Vec<String>,
+    /// Weight of each of the source's samples in the fit.
+    pub weight: f32,
+}
+
+/// A signal region where two sources disagree by more than `DISAGREEMENT_PCT`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disagreement {
+    pub from: f32,
+    pub to: f32,
+    pub sources: (usize, usize),
+    pub worst_pct: f32,
+}
+
+/// Summary of a session: coverage per source and warnings for conflicting sources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionReport {
+    pub signal: MafSignal,
+    pub coverage: Vec<SourceCoverage>,
+    pub disagreements: Vec<Disagreement>,
+}
+
+impl fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Coverage per source:")?;
+        for cov in &self.coverage {
+            write!(
+                f,
+                "  {}: {} of {} rows used, {:.2}-{:.2} {}, {} bins hit, {} channels",
+                cov.name, cov.samples, cov.rows, cov.min_x, cov.max_x, self.signal.unit(), cov.bins_hit, cov.channels.len()
+            )?;
+            if cov.wideband > 0 {
+                write!(
+                    f,
+                    ", {} corrected from wideband (mean AFR error {:+.1}%)",
+                    cov.wideband, cov.wideband_error
+                )?;
+            }
+            if cov.weight != 1.0 {
+                write!(f, ", weight {:.3} per sample", cov.weight)?;
+            }
+            writeln!(f)?;
+            if let Some(device) = &cov.device {
+                writeln!(f, "    logged with {}", device)?;
+            }
+        }
+        for d in &self.disagreements {
+            writeln!(
+                f,
+                "Warning: {} and {} disagree by up to {:.1}% between {:.2} {unit} and {:.2} {unit}",
+                self.coverage[d.sources.0].name, self.coverage[d.sources.1].name, d.worst_pct, d.from, d.to,
+                unit = self.signal.unit()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::LogField,
+        resample::Resampling,
+        rules::RuleSet,
+        testing::{log, row},
+    };
+
+    fn source(name: &str, rows: &[(f32, f32)]) -> LogSource {
+        let data = log(rows.iter().map(|&(x, maf)| row(x).with(LogField::MASS, maf)));
+        LogSource { name: name.to_string(), data }
+    }
+
+    /// Two logs over the same signal range, 30% apart in airflow.
+    fn disagreeing() -> Session {
+        let low: Vec<(f32, f32)> = (0..10).map(|i| (1.0 + i as f32 * 0.001, 10.0)).collect();
+        let high: Vec<(f32, f32)> = (0..10).map(|i| (1.0 + i as f32 * 0.001, 13.0)).collect();
+        Session { sources: vec![source("cold", &low), source("highway", &high)] }
+    }
+
+    #[test]
+    fn test_samples_are_tagged_by_source() {
+        let (samples, _) = disagreeing().samples(&CorrectionConfig::default()).unwrap();
+        assert_eq!(samples.len(), 20);
+        assert!(samples[..10].iter().all(|s| s.source == 0));
+        assert!(samples[10..].iter().all(|s| s.source == 1));
+    }
+
+    #[test]
+    fn test_report_flags_disagreement() {
+        let session = disagreeing();
+        let (samples, _) = session.samples(&CorrectionConfig::default()).unwrap();
+        let report = session.report(&samples);
+        assert_eq!(report.coverage[1].samples, 10);
+        assert_eq!(report.disagreements.len(), 1);
+        assert_eq!(report.disagreements[0].sources, (0, 1));
+        assert!(report.disagreements[0].from <= 1.0 && report.disagreements[0].to > 1.009);
+    }
+
+    /// Ten rows `interval` seconds apart, at MAF signals `x(i)`.
+    fn timed(name: &str, interval: f32, x: impl Fn(usize) -> f32) -> LogSource {
+        let data = log((0..10).map(|i| row(x(i)).with(LogField::TIME, i as f32 * interval)));
+        LogSource { name: name.to_string(), data }
+    }
+
+    /// Sums the weight of each source's points after deduplication, sources deduplicated apart.
+    fn deduplicated_weights(samples: &[Sample], sources: usize) -> Vec<f32> {
+        (0..sources)
+            .map(|source| {
+                let own: Vec<Sample> = samples.iter().filter(|s| s.source == source).copied().collect();
+                deduplicate(&own).2.iter().sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_time_weights_survive_deduplication() {
+        // One second of a fast log repeating two points, against ten seconds of distinct points
+        let session = Session {
+            sources: vec![
+                timed("fast", 0.1, |i| 1.0 + (i % 2) as f32 * 0.1),
+                timed("slow", 1.0, |i| 2.0 + i as f32 * 0.01),
+            ],
+        };
+        let config = CorrectionConfig { weighting: Weighting::Time, ..CorrectionConfig::default() };
+        let (samples, _) = session.samples(&config).unwrap();
+        let weights = deduplicated_weights(&samples, 2);
+        assert!((weights[0] / weights[1] - 0.1).abs() < 1e-4, "{:?}", weights);
+    }
+
+    #[test]
+    fn test_time_weights_follow_surviving_rows() {
+        // The blacklist leaves the last five seconds of the slow log out
+        let session = Session {
+            sources: vec![
+                timed("fast", 0.1, |i| 1.0 + i as f32 * 0.01),
+                timed("slow", 1.0, |i| 2.0 + i as f32 * 0.01),
+            ],
+        };
+        let config = CorrectionConfig {
+            weighting: Weighting::Time,
+            blacklist: Blacklist::parse("signal 2.045 3.0").unwrap(),
+            ..CorrectionConfig::default()
+        };
+        let (samples, _) = session.samples(&config).unwrap();
+        let weights = deduplicated_weights(&samples, 2);
+        assert!((weights[0] / weights[1] - 0.2).abs() < 1e-4, "{:?}", weights);
+    }
+
+    #[test]
+    fn test_rules_drop_rows_before_resampling() {
+        // A 4 ms log with one fuel-cut row carrying a 50% trim, averaged to 10 Hz
+        let data = log((0..50).map(|i| {
+            let (duty, stft) = if i == 10 { (0.0, 50.0) } else { (20.0, 0.0) };
+            row(2.0).with(LogField::TIME, i as f32 * 0.004).with(LogField::IDC, duty).with(LogField::STFT, stft)
+        }));
+        let session = Session { sources: vec![LogSource { name: "fuel cut".to_string(), data }] };
+        let config = CorrectionConfig {
+            rules: RuleSet::default_rules(),
+            resample: Some(Resampling::parse("10").unwrap()),
+            ..CorrectionConfig::default()
+        };
+        let (samples, dropped) = session.samples(&config).unwrap();
+        assert_eq!(samples.len(), 2);
+        assert!(samples.iter().all(|s| s.trim == 0.0), "{:?}", samples);
+        assert_eq!(dropped.drops.iter().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn test_deduplicate_counts_each_source() {
+        let samples = [
+            Sample::point(1.0, 10.0),
+            Sample::point(1.0, 10.0),
+            Sample { source: 1, weight: 0.5, ..Sample::point(1.0, 10.0) },
+        ];
+        assert_eq!(deduplicate(&samples), (vec![1.0], vec![10.0], vec![1.5]));
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    io::{self, Read, Write},
+    net::TcpListener,
+    time::{Duration, Instant},
+};
+use serialport::{SerialPort, TTYPort};
+use crate::{
+    data::{LogData, LogField},
+    elm327::{encode_maf_voltage, PIDS, PROMPT},
+};
+
+/// Row interval assumed for logs without a time column, in seconds.
+const DEFAULT_INTERVAL: f32 = 0.1;
+/// Identification the simulator answers `ATZ` and `ATI` with.
+const IDENTITY: &str = "ELM327 v1.5";
+
+/// An ELM327 that answers PID requests from a recorded log, replayed in real time.
+///
+/// Mode 01 requests for the PIDs in `elm327::PIDS` return the logged value of the current row,
+/// and any mode 22 request returns the MAF voltage. Values are sent in the units of the log.
+pub struct Simulator {
+    data: LogData,
+    time: Vec<f32>,
+    /// Time at which the last row has been replayed for as long as the rows before it.
+    end: f32,
+    /// Replay speed relative to real time.
+    speed: f32,
+    start: Option<Instant>,
+    echo: bool,
+}
+
+impl Simulator {
+    /// Replays `data` at `speed` times real time.
+    pub fn new(data: LogData, speed: f32) -> Self {
+        let time = match data.time() {
+            Some(time) => time.to_vec(),
+            None => (0..data.len()).map(|i| i as f32 * DEFAULT_INTERVAL).collect(),
+        };
+        let interval = match time[..] {
+            [.., previous, last] if last > previous => last - previous,
+            _ => DEFAULT_INTERVAL,
+        };
+        let end = time.last().map_or(0.0, |last| last + interval);
+        Simulator { data, time, end, speed, start: None, echo: true }
+    }
+
+    /// Returns the row being replayed, starting the clock on first use,
+    /// or `None` once the log is over.
+    fn row(&mut self) -> Option<usize> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.row_at(start.elapsed().as_secs_f32() * self.speed)
+    }
+
+    /// Returns the row replayed `elapsed` seconds of log time after the first,
+    /// or `None` once the last row has been replayed.
+    fn row_at(&self, elapsed: f32) -> Option<usize> {
+        let now = self.time.first()? + elapsed;
+        let row = self.time.partition_point(|&t| t <= now);
+        (now < self.end).then(|| row.saturating_sub(1))
+    }
+
+    /// Returns `true` once every row has been replayed.
+    pub fn finished(&mut self) -> bool {
+        self.start.is_some() && self.row().is_none()
+    }
+
+    /// Answers one command as an ELM327 with headers and spaces off would, without the prompt.
+    pub fn respond(&mut self, command: &str, row: Option<usize>) -> String {
+        let command = command.trim().to_uppercase().replace(' ', "");
+        if let Some(at) = command.strip_prefix("AT") {
+            return match at {
+                "Z" => {
+                    self.echo = true;
+                    IDENTITY.to_string()
+                }
+                "I" => IDENTITY.to_string(),
+                "E0" => {
+                    self.echo = false;
+                    "OK".to_string()
+                }
+                "E1" => {
+                    self.echo = true;
+                    "OK".to_string()
+                }
+                _ => "OK".to_string(),
+            };
+        }
+
+        let value = |field: LogField| {
+            row.and_then(|row| self.data.get(&field).map(|values| values[row])).filter(|v| v.is_finite())
+        };
+        let data = match (command.get(..2), command.get(2..4)) {
+            (Some("01"), Some(pid)) => u8::from_str_radix(pid, 16).ok()
+                .and_then(|code| PIDS.iter().find(|pid| pid.code == code))
+                .and_then(|pid| value(pid.field).map(|v| (pid.encode)(v))),
+            (Some("22"), Some(_)) => value(LogField::MAFV).map(encode_maf_voltage),
+            _ => return "?".to_string(),
+        };
+        match data {
+            Some(bytes) => {
+                let mode = u8::from_str_radix(&command[..2], 16).unwrap_or(0) + 0x40;
+                let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                format!("{:02X}{}{}", mode, &command[2..], hex)
+            }
+            None => "NO DATA".to_string(),
+        }
+    }
+
+    /// Serves commands on `port` until the log is over or the client disconnects.
+    pub fn serve<T: Read + Write>(&mut self, port: &mut T) -> io::Result<()> {
+        let mut command = Vec::new();
+        let mut byte = [0; 1];
+        while !self.finished() {
+            match port.read(&mut byte) {
+                Ok(0) => return Ok(()),
+                Ok(_) if byte[0] == b'\r' => {
+                    let text = String::from_utf8_lossy(&command).into_owned();
+                    command.clear();
+                    let row = if text.trim().to_uppercase().starts_with("AT") { None } else { self.row() };
+                    let mut reply = String::new();
+                    if self.echo {
+                        reply.push_str(&text);
+                        reply.push('\r');
+                    }
+                    reply.push_str(&self.respond(&text, row));
+                    reply.push_str("\r\r");
+                    reply.push(PROMPT as char);
+                    port.write_all(reply.as_bytes())?;
+                    port.flush()?;
+                }
+                Ok(_) if byte[0] != b'\n' => command.push(byte[0]),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replays `data` to one client of a TCP listener on `address`, e.g. `127.0.0.1:35000`.
+pub fn serve_tcp(data: LogData, address: &str, speed: f32) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!("Simulating an ELM327 on tcp:{}", listener.local_addr()?);
+    let (mut stream, peer) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    println!("Replaying the log to {}", peer);
+    Simulator::new(data, speed).serve(&mut stream)
+}
+
+/// Replays `data` on a new pseudo-terminal, whose path is printed for the logger to open.
+pub fn serve_pty(data: LogData, speed: f32) -> io::Result<()> {
+    let (mut master, slave) = TTYPort::pair().map_err(io::Error::other)?;
+    master.set_timeout(Duration::from_secs(1)).map_err(io::Error::other)?;
+    println!("Simulating an ELM327 on {}", slave.name().unwrap_or_default());
+    // The slave stays open so the terminal survives until the logger opens it
+    let result = Simulator::new(data, speed).serve(&mut master);
+    drop(slave);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::{
+        blacklist::Blacklist,
+        correction::{correct, CorrectionConfig},
+        elm327::Elm327,
+        log_reader::{parse_log, read_log},
+        logger::{log_to_csv, LoggerConfig},
+        testing::{log, row},
+    };
+
+    fn recorded() -> LogData {
+        log((0..3).map(|i| {
+            row(2.5)
+                .with(LogField::TIME, i as f32 * 0.5)
+                .with(LogField::MASS, 30.0)
+                .with(LogField::STFT, 2.34375)
+                .with(LogField::LTFT, -1.5625)
+                .with(LogField::RPM, 3000.0)
+        }))
+    }
+
+    #[test]
+    fn test_at_commands() {
+        let mut simulator = Simulator::new(recorded(), 1.0);
+        assert_eq!(simulator.respond("ATI", None), IDENTITY);
+        assert_eq!(simulator.respond("ATE0", None), "OK");
+    }
+
+    #[test]
+    fn test_pid_responses() {
+        let mut simulator = Simulator::new(recorded(), 1.0);
+        assert_eq!(simulator.respond("010C", Some(0)), "410C2EE0");
+        assert_eq!(simulator.respond("01 10", Some(0)), "41100BB8");
+        assert_eq!(simulator.respond("221001", Some(1)), "62100109C4");
+    }
+
+    #[test]
+    fn test_replay_serves_every_row() {
+        let simulator = Simulator::new(recorded(), 1.0);
+        let rows: Vec<Option<usize>> = [0.0, 0.4, 0.5, 0.99, 1.0, 1.49, 1.5].iter().map(|&t| simulator.row_at(t)).collect();
+        assert_eq!(rows, vec![Some(0), Some(0), Some(1), Some(1), Some(2), Some(2), None]);
+    }
+
+    #[test]
+    fn test_finished_after_last_row() {
+        let mut simulator = Simulator::new(recorded(), 100.0);
+        let mut served = Vec::new();
+        while !simulator.finished() {
+            if let Some(row) = simulator.row() {
+                if served.last() != Some(&row) {
+                    served.push(row);
+                }
+            }
+        }
+        assert_eq!(served, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_unlogged_pid_has_no_data() {
+        let mut simulator = Simulator::new(recorded(), 1.0);
+        assert_eq!(simulator.respond("010F", Some(1)), "NO DATA");
+    }
+
+    /// Logs three rows from a simulator replaying `recorded`, returning the CSV the logger wrote.
+    fn logged_csv() -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            Simulator::new(recorded(), 1.0).serve(&mut stream).unwrap();
+        });
+
+        let config = LoggerConfig {
+            adapter: format!("tcp:{}", address),
+            baud: 0,
+            maf_voltage: "221001".to_string(),
+            rows: Some(3),
+            display: false,
+        };
+        let mut adapter = Elm327::new(crate::logger::connect(&config).unwrap());
+        adapter.init().unwrap();
+        let mut csv = Vec::new();
+        assert_eq!(log_to_csv(&mut adapter, &mut csv, &config).unwrap(), 3);
+        drop(adapter);
+        server.join().unwrap();
+        csv
+    }
+
+    #[test]
+    fn test_logger_against_simulator() {
+        let data = parse_log(&logged_csv()[..], "live.csv", &Blacklist::default()).unwrap();
+        assert_eq!(data.len(), 3);
+        assert_eq!(data.get(&LogField::MAFV).unwrap()[0], 2.5);
+        assert_eq!(data.get(&LogField::MASS).unwrap()[0], 30.0);
+        assert_eq!(data.get(&LogField::STFT).unwrap()[0], 2.344);
+        assert_eq!(data.get(&LogField::RPM).unwrap()[0], 3000.0);
+        assert!(data.get(&LogField::IAT).unwrap()[0].is_nan());
+    }
+
+    #[test]
+    fn test_logged_csv_can_be_fitted() {
+        let path = std::env::temp_dir().join(format!("maf_cal_logged_{}.csv", std::process::id()));
+        std::fs::write(&path, logged_csv()).unwrap();
+        let data = read_log(&path, &Blacklist::default());
+        std::fs::remove_file(&path).unwrap();
+        let samples = correct(&data.unwrap(), 0, &CorrectionConfig::default()).unwrap();
+        assert_eq!(samples.len(), 3);
+        assert!(samples.iter().all(|s| s.x == 2.5 && s.y.is_finite()));
+    }
+}
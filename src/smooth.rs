@@ -0,0 +1,412 @@
+use std::{fmt, io};
+use crate::{
+    data::interpolate,
+    session::Sample,
+    table::{invalid, CalibrationTable},
+};
+
+/// Weight that pins a cell the smoothing spline may not move.
+const PINNED_WEIGHT: f64 = 1e9;
+
+/// A smoothing operator over table cells. Widths are counted in cells.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kernel {
+    /// Mean of the `width` cells centred on each cell.
+    MovingAverage { width: usize },
+    /// Mean weighted by a normal curve of `sigma` cells, cut off at three sigma.
+    Gaussian { sigma: f32 },
+    /// Value at each cell of a polynomial of `order` fitted to the `width` cells centred on it.
+    /// Keeps peaks and slopes better than a moving average of the same width.
+    SavitzkyGolay { width: usize, order: usize },
+    /// Penalized least squares: stays close to the cells while penalizing their second differences
+    /// by `lambda`. Larger values give a stiffer curve.
+    Spline { lambda: f32 },
+}
+
+impl Kernel {
+    /// Parses `ma:WIDTH`, `gaussian:SIGMA`, `sg:WIDTH:ORDER` or `spline:LAMBDA`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.split(':');
+        let name = parts.next().unwrap_or_default();
+        let mut next = |what: &str| parts.next().ok_or_else(|| format!("'{}' needs a {}", name, what));
+        // Widths and orders are whole cells, sigma and lambda may be fractional
+        let count = |what: &str, value: &str| {
+            value.parse::<usize>().ok()
+                .filter(|&v| v > 0)
+                .ok_or_else(|| format!("invalid {} '{}', expected a whole number of cells", what, value))
+        };
+        let number = |what: &str, value: &str| {
+            value.parse::<f32>().ok()
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .ok_or_else(|| format!("invalid {} '{}'", what, value))
+        };
+        let kernel = match name {
+            "ma" => Kernel::MovingAverage { width: count("width", next("width")?)? },
+            "gaussian" => Kernel::Gaussian { sigma: number("sigma", next("sigma")?)? },
+            "sg" => Kernel::SavitzkyGolay { width: count("width", next("width")?)?, order: count("order", next("order")?)? },
+            "spline" => Kernel::Spline { lambda: number("lambda", next("lambda")?)? },
+            _ => return Err(format!("unknown kernel '{}', expected ma, gaussian, sg or spline", name)),
+        };
+        if parts.next().is_some() {
+            return Err(format!("too many parameters in '{}'", text));
+        }
+        match kernel {
+            Kernel::MovingAverage { width } | Kernel::SavitzkyGolay { width, .. } if width % 2 == 0 => {
+                Err(format!("width must be odd, found {}", width))
+            }
+            Kernel::SavitzkyGolay { width, order } if order >= width => {
+                Err(format!("order {} needs a width above it, found {}", order, width))
+            }
+            kernel => Ok(kernel),
+        }
+    }
+
+    /// Returns `true` if the window of the kernel fits in a table of `cells` cells.
+    /// The spline has no window and fits any table.
+    pub fn fits(&self, cells: usize) -> bool {
+        match *self {
+            Kernel::MovingAverage { width } | Kernel::SavitzkyGolay { width, .. } => width <= cells,
+            Kernel::Gaussian { sigma } => 2.0 * (3.0 * sigma).ceil() < cells as f32,
+            Kernel::Spline { .. } => true,
+        }
+    }
+
+    /// Returns `values` with every cell where `apply` is set smoothed. Other cells are unchanged,
+    /// but still count as neighbours of the cells being smoothed.
+    pub fn smooth(&self, values: &[f32], apply: &[bool]) -> Vec<f32> {
+        let mut smoothed = match *self {
+            Kernel::MovingAverage { width } => weighted(values, width / 2, |_| 1.0),
+            Kernel::Gaussian { sigma } => {
+                weighted(values, (3.0 * sigma).ceil() as usize, |d| (-0.5 * (d as f32 / sigma).powi(2)).exp())
+            }
+            Kernel::SavitzkyGolay { width, order } => savitzky_golay(values, width / 2, order),
+            Kernel::Spline { lambda } => spline(values, apply, lambda),
+        };
+        for ((smoothed, &value), &apply) in smoothed.iter_mut().zip(values).zip(apply) {
+            if !apply {
+                *smoothed = value;
+            }
+        }
+        smoothed
+    }
+}
+
+impl fmt::Display for Kernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kernel::MovingAverage { width } => write!(f, "moving average over {} cells", width),
+            Kernel::Gaussian { sigma } => write!(f, "Gaussian, sigma {} cells", sigma),
+            Kernel::SavitzkyGolay { width, order } => write!(f, "Savitzky-Golay over {} cells, order {}", width, order),
+            Kernel::Spline { lambda } => write!(f, "smoothing spline, lambda {}", lambda),
+        }
+    }
+}
+
+/// Weighted mean of each cell and up to `reach` cells either side, by distance in cells.
+/// The window is cut short at the ends of the table.
+fn weighted(values: &[f32], reach: usize, weight: impl Fn(usize) -> f32) -> Vec<f32> {
+    (0..values.len())
+        .map(|i| {
+            let window = i.saturating_sub(reach)..(i + reach + 1).min(values.len());
+            let (sum, total) = window.fold((0.0, 0.0), |(sum, total), j| {
+                let w = weight(i.abs_diff(j));
+                (sum + values[j] * w, total + w)
+            });
+            sum / total
+        })
+        .collect()
+}
+
+/// Fits a polynomial of `order` by least squares to each cell and up to `reach` cells either side,
+/// and takes its value at the cell. Near the ends the window is cut short and the order lowered to fit.
+fn savitzky_golay(values: &[f32], reach: usize, order: usize) -> Vec<f32> {
+    (0..values.len())
+        .map(|i| {
+            let window: Vec<usize> = (i.saturating_sub(reach)..(i + reach + 1).min(values.len())).collect();
+            let terms = (order + 1).min(window.len());
+            // Normal equations of the polynomial in the offset from cell i
+            let mut matrix = vec![vec![0.0; terms]; terms];
+            let mut rhs = vec![0.0; terms];
+            for &j in &window {
+                let t = j as f64 - i as f64;
+                for (a, (row, rhs)) in matrix.iter_mut().zip(rhs.iter_mut()).enumerate() {
+                    *rhs += t.powi(a as i32) * values[j] as f64;
+                    for (b, cell) in row.iter_mut().enumerate() {
+                        *cell += t.powi((a + b) as i32);
+                    }
+                }
+            }
+            solve(matrix, rhs).map_or(values[i], |coefficients| coefficients[0] as f32)
+        })
+        .collect()
+}
+
+/// Whittaker smoother: minimizes the squared distance to `values` plus `lambda` times the squared
+/// second differences. Cells not in `apply` are pinned to their value.
+fn spline(values: &[f32], apply: &[bool], lambda: f32) -> Vec<f32> {
+    let n = values.len();
+    if n < 3 {
+        return values.to_vec();
+    }
+    let lambda = lambda as f64;
+    let mut matrix = vec![vec![0.0; n]; n];
+    let mut rhs = vec![0.0; n];
+    for i in 0..n {
+        let weight = if apply[i] { 1.0 } else { PINNED_WEIGHT };
+        matrix[i][i] += weight;
+        rhs[i] = weight * values[i] as f64;
+    }
+    // Add lambda * D'D, with D the second difference operator
+    for k in 0..n - 2 {
+        let row = [(k, 1.0), (k + 1, -2.0), (k + 2, 1.0)];
+        for &(a, da) in &row {
+            for &(b, db) in &row {
+                matrix[a][b] += lambda * da * db;
+            }
+        }
+    }
+    solve(matrix, rhs).map_or_else(|| values.to_vec(), |z| z.iter().map(|&z| z as f32).collect())
+}
+
+/// Solves `matrix * x = rhs` by Gaussian elimination with partial pivoting.
+/// Returns `None` if the matrix is singular.
+fn solve(mut matrix: Vec<Vec<f64>>, mut rhs: Vec<f64>) -> Option<Vec<f64>> {
+    let n = rhs.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&a, &b| matrix[a][col].abs().total_cmp(&matrix[b][col].abs()))?;
+        if matrix[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        matrix.swap(col, pivot);
+        rhs.swap(col, pivot);
+        let (above, below) = matrix.split_at_mut(col + 1);
+        let pivot_row = &above[col];
+        for (offset, row) in below.iter_mut().enumerate() {
+            let factor = row[col] / pivot_row[col];
+            if factor == 0.0 {
+                continue;
+            }
+            for (cell, &pivot) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *cell -= factor * pivot;
+            }
+            rhs[col + 1 + offset] -= factor * rhs[col];
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| matrix[row][k] * x[k]).sum();
+        x[row] = (rhs[row] - sum) / matrix[row][row];
+    }
+    Some(x)
+}
+
+/// A smoothing pass over a table, optionally limited to a range of the MAF signal.
+/// Cells in any of the `locks` ranges keep their value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Smoothing {
+    pub kernel: Kernel,
+    pub range: Option<(f32, f32)>,
+    pub locks: Vec<(f32, f32)>,
+}
+
+impl Smoothing {
+    /// Smooths the cells of `table` inside the range, leaving cells marked in `locked` as they are.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the kernel is wider than the table.
+    pub fn apply(&self, table: &CalibrationTable, locked: &[bool]) -> io::Result<CalibrationTable> {
+        if !self.kernel.fits(table.values.len()) {
+            return Err(invalid(format!("The {} is wider than the table's {} cells", self.kernel, table.values.len())));
+        }
+        let apply: Vec<bool> = table.axis.iter().enumerate()
+            .map(|(i, &x)| {
+                !locked.get(i).copied().unwrap_or(false)
+                    && self.range.is_none_or(|(from, to)| x >= from && x <= to)
+                    && !self.locks.iter().any(|&(from, to)| x >= from && x <= to)
+            })
+            .collect();
+        Ok(CalibrationTable { values: self.kernel.smooth(&table.values, &apply), ..table.clone() })
+    }
+
+    /// Smooths `table` and reports how the residuals of `samples` against it changed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the kernel is wider than the table.
+    pub fn apply_with_report(&self, table: &CalibrationTable, locked: &[bool], samples: &[Sample]) -> io::Result<(CalibrationTable, SmoothingReport)> {
+        let smoothed = self.apply(table, locked)?;
+        let changes: Vec<f32> = table.values.iter().zip(&smoothed.values)
+            .filter(|(before, after)| before != after)
+            .map(|(&before, &after)| if before.abs() > f32::EPSILON { (after / before - 1.0).abs() * 100.0 } else { 0.0 })
+            .collect();
+        let report = SmoothingReport {
+            smoothing: self.clone(),
+            unit: table.signal.unit(),
+            changed: changes.len(),
+            max_change_pct: changes.iter().copied().fold(0.0, f32::max),
+            rms_before: rms_residual(table, samples),
+            rms_after: rms_residual(&smoothed, samples),
+        };
+        Ok((smoothed, report))
+    }
+}
+
+/// Returns the mean corrected airflow of the samples nearest each cell of `axis`, by their weight,
+/// or `None` for a cell no sample is nearest to. Samples outside the axis are left out.
+pub fn cell_airflow(axis: &[f32], samples: &[Sample]) -> Vec<Option<f32>> {
+    let mut sums = vec![(0.0, 0.0); axis.len()];
+    for sample in samples.iter().filter(|s| s.y.is_finite()) {
+        let Some(cell) = nearest_cell(axis, sample.x) else { continue };
+        sums[cell].0 += sample.weight * sample.y;
+        sums[cell].1 += sample.weight;
+    }
+    sums.into_iter().map(|(sum, weight)| (weight > 0.0).then(|| sum / weight)).collect()
+}
+
+/// Returns the cell of `axis` nearest `x`, or `None` if `x` lies outside the axis.
+fn nearest_cell(axis: &[f32], x: f32) -> Option<usize> {
+    if !(x >= *axis.first()? && x <= *axis.last()?) {
+        return None;
+    }
+    let next = axis.partition_point(|&a| a < x);
+    match next.checked_sub(1) {
+        Some(previous) if x - axis[previous] < axis[next] - x => Some(previous),
+        _ => Some(next),
+    }
+}
+
+/// Root mean square of the samples' airflow minus the table interpolated at their signal,
+/// over the samples inside the table's axis.
+pub fn rms_residual(table: &CalibrationTable, samples: &[Sample]) -> f32 {
+    let (sum, count) = samples.iter()
+        .map(|s| s.y - interpolate(&table.axis, &table.values, s.x))
+        .filter(|r| r.is_finite())
+        .fold((0.0f64, 0usize), |(sum, count), r| (sum + (r * r) as f64, count + 1));
+    if count == 0 { f32::NAN } else { (sum / count as f64).sqrt() as f32 }
+}
+
+/// How much a smoothing pass changed a table and the fit of the samples to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmoothingReport {
+    pub smoothing: Smoothing,
+    pub unit: &'static str,
+    pub changed: usize,
+    pub max_change_pct: f32,
+    pub rms_before: f32,
+    pub rms_after: f32,
+}
+
+impl fmt::Display for SmoothingReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Smoothing: {}", self.smoothing.kernel)?;
+        if let Some((from, to)) = self.smoothing.range {
+            write!(f, " from {} to {} {}", from, to, self.unit)?;
+        }
+        writeln!(f)?;
+        for (from, to) in &self.smoothing.locks {
+            writeln!(f, "  Locked from {} to {} {}", from, to, self.unit)?;
+        }
+        writeln!(f, "  {} cells changed, by up to {:.1}%", self.changed, self.max_change_pct)?;
+        writeln!(f, "  RMS residual of the samples: {:.3} g/s before, {:.3} g/s after", self.rms_before, self.rms_after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [bool; 7] = [true; 7];
+    const SPIKE: [f32; 7] = [1.0, 1.0, 1.0, 8.0, 1.0, 1.0, 1.0];
+
+    #[test]
+    fn test_moving_average() {
+        let average = Kernel::parse("ma:3").unwrap().smooth(&SPIKE, &ALL);
+        assert_eq!(average, vec![1.0, 1.0, 10.0 / 3.0, 10.0 / 3.0, 10.0 / 3.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_savitzky_golay_keeps_quadratic() {
+        // A quadratic passes through Savitzky-Golay of order 2 unchanged, also at the ends
+        let quadratic: Vec<f32> = (0..7).map(|i| (i * i) as f32).collect();
+        let sg = Kernel::parse("sg:5:2").unwrap().smooth(&quadratic, &ALL);
+        assert!(sg.iter().zip(&quadratic).all(|(a, b)| (a - b).abs() < 1e-3), "{:?}", sg);
+    }
+
+    #[test]
+    fn test_spline_keeps_line() {
+        // A straight line has no second differences for the spline to remove
+        let line: Vec<f32> = (0..7).map(|i| 2.0 * i as f32 + 1.0).collect();
+        let spline = Kernel::parse("spline:100").unwrap().smooth(&line, &ALL);
+        assert!(spline.iter().zip(&line).all(|(a, b)| (a - b).abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_gaussian() {
+        let gaussian = Kernel::parse("gaussian:1").unwrap().smooth(&SPIKE, &ALL);
+        assert!(gaussian[3] < 8.0 && gaussian[2] > 1.0);
+        assert!((gaussian[2] - gaussian[4]).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_kernels() {
+        assert!(Kernel::parse("ma:4").is_err());
+        assert!(Kernel::parse("sg:5:5").is_err());
+        assert!(Kernel::parse("box:3").is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_fractional_cells() {
+        assert!(Kernel::parse("ma:3.7").is_err());
+        assert!(Kernel::parse("sg:5:2.5").is_err());
+        assert_eq!(Kernel::parse("gaussian:1.5"), Ok(Kernel::Gaussian { sigma: 1.5 }));
+    }
+
+    #[test]
+    fn test_kernel_wider_than_table() {
+        let table = CalibrationTable::new((1..=7).map(|i| i as f32).collect(), SPIKE.to_vec()).unwrap();
+        let smoothing = |kernel: &str| Smoothing { kernel: Kernel::parse(kernel).unwrap(), range: None, locks: Vec::new() };
+        assert!(smoothing("ma:9").apply(&table, &[]).is_err());
+        assert!(smoothing("gaussian:1e30").apply(&table, &[]).is_err());
+        assert!(smoothing("gaussian:1").apply(&table, &[]).is_ok());
+    }
+
+    #[test]
+    fn test_range_and_locks() {
+        let table = CalibrationTable::new(vec![1.0, 2.0, 3.0, 4.0, 5.0], vec![1.0, 5.0, 1.0, 5.0, 1.0]).unwrap();
+        let smoothing = Smoothing { kernel: Kernel::Spline { lambda: 10.0 }, range: Some((1.5, 4.5)), locks: Vec::new() };
+        let smoothed = smoothing.apply(&table, &[false, false, true, false, false]).unwrap();
+        assert_eq!((smoothed.values[0], smoothed.values[2], smoothed.values[4]), (1.0, 1.0, 1.0));
+        assert!(smoothed.values[1] < 5.0 && smoothed.values[3] < 5.0);
+    }
+
+    #[test]
+    fn test_user_locks() {
+        let table = CalibrationTable::new(vec![1.0, 2.0, 3.0, 4.0, 5.0], vec![1.0, 5.0, 1.0, 5.0, 1.0]).unwrap();
+        let smoothing = Smoothing { kernel: Kernel::parse("ma:3").unwrap(), range: None, locks: vec![(1.5, 2.5)] };
+        let smoothed = smoothing.apply(&table, &[]).unwrap();
+        assert_eq!(smoothed.values[1], 5.0);
+        assert!((smoothed.values[3] - 7.0 / 3.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_cell_airflow() {
+        let samples = [
+            Sample::point(1.1, 4.0),
+            Sample { weight: 3.0, ..Sample::point(1.4, 8.0) },
+            Sample::point(2.6, 6.0),
+            Sample::point(3.5, 50.0),
+        ];
+        assert_eq!(cell_airflow(&[1.0, 2.0, 3.0], &samples), vec![Some(7.0), None, Some(6.0)]);
+    }
+
+    #[test]
+    fn test_residual_report() {
+        let table = CalibrationTable::new(vec![1.0, 2.0, 3.0, 4.0, 5.0], vec![1.0, 5.0, 1.0, 5.0, 1.0]).unwrap();
+        let smoothing = Smoothing { kernel: Kernel::Spline { lambda: 10.0 }, range: Some((1.5, 4.5)), locks: Vec::new() };
+        let samples = [Sample::point(2.0, 3.0), Sample::point(4.0, 3.0)];
+        let (_, report) = smoothing.apply_with_report(&table, &[], &samples).unwrap();
+        assert_eq!(report.changed, 3);
+        assert!(report.rms_after < report.rms_before);
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    fs,
+    io,
+    path::Path,
+};
+use serde::{Deserialize, Serialize};
+use crate::data::MafSignal;
+
+/// Separators accepted between table cells, in order of preference.
+const SEPARATORS: [char; 3] = ['\t', ';', ','];
+
+/// A MAF scaling table: airflow in g/s for each point of the MAF signal axis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationTable {
+    pub axis: Vec<f32>,
+    pub values: Vec<f32>,
+    #[serde(default)]
+    pub signal: MafSignal,
+}
+
+impl CalibrationTable {
+    /// Creates a table, checking that axis and values line up and that the axis is strictly increasing.
+    /// The signal is guessed from the axis values.
+    pub fn new(axis: Vec<f32>, values: Vec<f32>) -> io::Result<Self> {
+        if axis.len() != values.len() {
+            return Err(invalid(format!("Axis has {} cells but values have {}", axis.len(), values.len())));
+        }
+        if axis.is_empty() {
+            return Err(invalid("Table has no cells".to_string()));
+        }
+        if let Some(i) = axis.windows(2).position(|pair| pair[0].partial_cmp(&pair[1]) != Some(std::cmp::Ordering::Less)) {
+            return Err(invalid(format!(
+                "Axis is not strictly increasing at cell {}: {} then {}",
+                i + 2, axis[i], axis[i + 1]
+            )));
+        }
+        let signal = MafSignal::from_axis(&axis);
+        Ok(CalibrationTable { axis, values, signal })
+    }
+
+    /// Loads a table from a file, see `parse` for the accepted layouts.
+    /// Bytes that are not valid UTF-8 only matter inside labels, so they are replaced rather than rejected.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&bytes))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses a table as written by hand or copied from tuning software.
+    ///
+    /// * Cells may be separated by tabs, semicolons or commas. With semicolons, a comma
+    ///   inside a cell is read as a decimal comma.
+    /// * Rows where no cell is a number are headers and are skipped, as are blank lines.
+    ///   A text label in the first cell of a row is ignored.
+    /// * The signal comes from a header or label naming volts or Hz, otherwise from the axis values.
+    /// * Column orientation has one `voltage, g/s` pair per line. Row orientation has
+    ///   the voltage axis on one line and the g/s values on the next.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a cell cannot be read, the layout is neither orientation,
+    /// or the axis is not strictly increasing.
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let separator = detect_separator(text);
+        let decimal_comma = separator == ';';
+
+        let mut rows: Vec<Vec<f32>> = Vec::new();
+        let mut signal = None;
+        for (number, line) in text.lines().enumerate() {
+            // Blank cells are dropped, but each cell keeps its column for error messages
+            let cells: Vec<(usize, &str)> = line.split(separator)
+                .map(str::trim)
+                .enumerate()
+                .filter(|(_, cell)| !cell.is_empty())
+                .collect();
+            let parsed: Vec<Option<f32>> = cells.iter().map(|(_, cell)| parse_cell(cell, decimal_comma)).collect();
+            if parsed.iter().all(Option::is_none) {
+                signal = signal.or_else(|| cells.iter().find_map(|(_, cell)| MafSignal::from_header(cell)));
+                continue;
+            }
+            // A leading text cell is a row label
+            let skip = usize::from(parsed[0].is_none());
+            if skip == 1 {
+                signal = signal.or_else(|| MafSignal::from_header(cells[0].1));
+            }
+            let row = parsed[skip..].iter().zip(&cells[skip..])
+                .map(|(&value, &(column, cell))| cell_value(value, cell, number, column))
+                .collect::<io::Result<Vec<f32>>>()?;
+            rows.push(row);
+        }
+
+        let mut table = match rows.as_slice() {
+            [axis, values] if axis.len() > 2 || values.len() > 2 => Self::new(axis.clone(), values.clone())?,
+            _ if !rows.is_empty() && rows.iter().all(|row| row.len() == 2) => {
+                let (axis, values) = rows.iter().map(|row| (row[0], row[1])).unzip();
+                Self::new(axis, values)?
+            }
+            [] => return Err(invalid("Table has no cells".to_string())),
+            _ => return Err(invalid(
+                "Expected one signal/airflow pair per line, or an axis line followed by a value line".to_string(),
+            )),
+        };
+        if let Some(signal) = signal {
+            table.signal = signal;
+        }
+        Ok(table)
+    }
+
+    /// Returns `true` if the table has no cells.
+    pub fn is_empty(&self) -> bool {
+        self.axis.is_empty()
+    }
+}
+
+/// Picks the first separator in `SEPARATORS` that appears in `text`, defaulting to a comma.
+pub fn detect_separator(text: &str) -> char {
+    SEPARATORS.into_iter().find(|&sep| text.contains(sep)).unwrap_or(',')
+}
+
+/// Parses one table cell, reading a comma as the decimal point if `decimal_comma` is set.
+/// `nan` and `inf` parse too, so they are not mistaken for labels; `cell_value` rejects them.
+pub fn parse_cell(cell: &str, decimal_comma: bool) -> Option<f32> {
+    if decimal_comma {
+        cell.replace(',', ".").parse().ok()
+    } else {
+        cell.parse().ok()
+    }
+}
+
+/// Returns the value `parse_cell` read from the cell at zero-based `line` and `column`,
+/// or an error naming the cell if it is not a finite number.
+pub fn cell_value(value: Option<f32>, cell: &str, line: usize, column: usize) -> io::Result<f32> {
+    match value {
+        Some(value) if value.is_finite() => Ok(value),
+        Some(_) => Err(invalid(format!("Line {}, column {}: '{}' is not a finite number", line + 1, column + 1, cell))),
+        None => Err(invalid(format!("Line {}, column {}: '{}' is not a number", line + 1, column + 1, cell))),
+    }
+}
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_layouts() {
+        let expected = CalibrationTable::new(vec![0.5, 1.0, 1.5], vec![2.0, 4.25, 8.0]).unwrap();
+
+        let column = "Voltage (V),Airflow (g/s)\n0.5, 2\n\n1.0,4.25\n 1.5 ,8\n";
+        assert_eq!(CalibrationTable::parse(column).unwrap(), expected);
+
+        let row = "\t0.5\t1.0\t1.5\r\nMAF g/s\t2\t4.25\t8\r\n";
+        assert_eq!(CalibrationTable::parse(row).unwrap(), expected);
+
+        let semicolon = "V;g/s\n0,5;2\n1,0;4,25\n1,5;8\n";
+        assert_eq!(CalibrationTable::parse(semicolon).unwrap(), expected);
+
+        let stock = CalibrationTable::load("./data/stock1.csv").unwrap();
+        assert_eq!(stock.axis.len(), 129);
+        assert_eq!(stock.signal, MafSignal::Voltage);
+
+        let frequency = CalibrationTable::parse("MAF Freq (Hz),g/s\n1500,2\n3000,8\n").unwrap();
+        assert_eq!(frequency.signal, MafSignal::Frequency);
+        let unlabelled = CalibrationTable::parse("1500,2\n3000,8\n").unwrap();
+        assert_eq!(unlabelled.signal, MafSignal::Frequency);
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_tables() {
+        assert!(CalibrationTable::parse("1.0,2\n0.5,3\n").is_err());
+        assert!(CalibrationTable::parse("1.0,2\n1.0,3\n").is_err());
+        assert!(CalibrationTable::parse("0.5,2\n1.0,x\n").is_err());
+        assert!(CalibrationTable::parse("\n\n").is_err());
+
+        let error = CalibrationTable::parse("0.5,2\n1.0,inf\n1.5,8\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 2, column 2: 'inf' is not a finite number");
+        let error = CalibrationTable::parse("0.5;1,0;1,5\nNaN;;4;8\n").unwrap_err();
+        assert_eq!(error.to_string(), "Line 2, column 1: 'NaN' is not a finite number");
+    }
+}
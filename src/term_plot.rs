@@ -0,0 +1,208 @@
+use std::{
+    env,
+    fmt::Write as _,
+};
+use crate::{
+    bins::{BinStats, Bins},
+    data::MafSignal,
+};
+
+/// Plot width in characters when the terminal width is unknown.
+const DEFAULT_WIDTH: usize = 72;
+/// Height of the sample plot, in characters.
+const HEIGHT: usize = 16;
+/// Height of the per-bin change profile, in characters.
+const PROFILE_HEIGHT: usize = 8;
+/// Characters reserved left of the plot for Y labels.
+const LABEL_WIDTH: usize = 9;
+/// First braille pattern; each dot of a 2x4 cell adds one bit.
+const BRAILLE: u32 = 0x2800;
+/// Bit of each dot in a braille cell, indexed by `[row][column]`.
+const DOT_BITS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+/// ANSI colour for highlighted dots, and the reset after them.
+const HIGHLIGHT: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+/// A grid of braille cells, each holding 2x4 dots, mapped onto an X and Y range.
+/// Dots drawn by `line` are highlighted so a curve stands out from scattered points.
+pub struct Canvas {
+    width: usize,
+    height: usize,
+    x: (f32, f32),
+    y: (f32, f32),
+    dots: Vec<u8>,
+    highlight: Vec<u8>,
+}
+
+impl Canvas {
+    /// Creates an empty canvas of `width` x `height` characters showing `x` and `y`.
+    pub fn new(width: usize, height: usize, x: (f32, f32), y: (f32, f32)) -> Self {
+        Canvas { width, height, x, y, dots: vec![0; width * height], highlight: vec![0; width * height] }
+    }
+
+    /// Returns the dot column and row of a point, or `None` outside the ranges.
+    fn to_dot(&self, x: f32, y: f32) -> Option<(usize, usize)> {
+        let fx = (x - self.x.0) / (self.x.1 - self.x.0).max(f32::EPSILON);
+        let fy = (self.y.1 - y) / (self.y.1 - self.y.0).max(f32::EPSILON);
+        if !(0.0..=1.0).contains(&fx) || !(0.0..=1.0).contains(&fy) {
+            return None;
+        }
+        let column = (fx * (self.width * 2 - 1) as f32).round() as usize;
+        let row = (fy * (self.height * 4 - 1) as f32).round() as usize;
+        Some((column, row))
+    }
+
+    fn set(&mut self, column: usize, row: usize, highlight: bool) {
+        let cell = row / 4 * self.width + column / 2;
+        let bit = DOT_BITS[row % 4][column % 2];
+        self.dots[cell] |= bit;
+        if highlight {
+            self.highlight[cell] |= bit;
+        }
+    }
+
+    /// Draws a single dot at `(x, y)`.
+    pub fn point(&mut self, x: f32, y: f32) {
+        if let Some((column, row)) = self.to_dot(x, y) {
+            self.set(column, row, false);
+        }
+    }
+
+    /// Draws a highlighted line through `points`, skipping segments with an end outside the ranges.
+    pub fn line(&mut self, points: &[(f32, f32)]) {
+        for pair in points.windows(2) {
+            let (Some(a), Some(b)) = (self.to_dot(pair[0].0, pair[0].1), self.to_dot(pair[1].0, pair[1].1)) else {
+                continue;
+            };
+            let steps = a.0.abs_diff(b.0).max(a.1.abs_diff(b.1)).max(1);
+            for i in 0..=steps {
+                let t = i as f32 / steps as f32;
+                let column = (a.0 as f32 + (b.0 as f32 - a.0 as f32) * t).round() as usize;
+                let row = (a.1 as f32 + (b.1 as f32 - a.1 as f32) * t).round() as usize;
+                self.set(column, row, true);
+            }
+        }
+    }
+
+    /// Renders the canvas with its Y range on the left and X range below.
+    /// With `color`, highlighted cells are drawn in red.
+    pub fn render(&self, color: bool) -> String {
+        let mut text = String::new();
+        for row in 0..self.height {
+            let label = match row {
+                0 => format!("{:.1}", self.y.1),
+                r if r == self.height - 1 => format!("{:.1}", self.y.0),
+                _ => String::new(),
+            };
+            let _ = write!(text, "{:>width$} │", label, width = LABEL_WIDTH - 2);
+            for cell in row * self.width..(row + 1) * self.width {
+                let glyph = char::from_u32(BRAILLE + self.dots[cell] as u32).unwrap_or(' ');
+                if color && self.highlight[cell] != 0 {
+                    let _ = write!(text, "{}{}{}", HIGHLIGHT, glyph, RESET);
+                } else {
+                    text.push(glyph);
+                }
+            }
+            text.push('\n');
+        }
+        let _ = writeln!(text, "{:>width$} └{}", "", "─".repeat(self.width), width = LABEL_WIDTH - 2);
+        let (low, high) = (format!("{:.2}", self.x.0), format!("{:.2}", self.x.1));
+        let gap = (self.width + 1).saturating_sub(low.len() + high.len());
+        let _ = writeln!(text, "{:>width$}{}{}{}", "", low, " ".repeat(gap), high, width = LABEL_WIDTH - 1);
+        text
+    }
+}
+
+/// Returns the smallest and largest finite value, widened if they are equal.
+fn range(values: impl Iterator<Item = f32>) -> (f32, f32) {
+    let (low, high) = values.filter(|v| v.is_finite())
+        .fold((f32::MAX, f32::MIN), |(low, high), v| (low.min(v), high.max(v)));
+    match (low, high) {
+        (low, high) if low > high => (0.0, 1.0),
+        (low, high) if (high - low).abs() < f32::EPSILON => (low - 0.5, high + 0.5),
+        range => range,
+    }
+}
+
+/// Returns the percentage the fitted curve differs from the mean corrected sample in each bin,
+/// at the bin centres.
+pub fn change_profile(x: &[f32], y: &[f32], y_fit: &[f32]) -> Vec<(f32, f32)> {
+    let bins = Bins::for_signal(MafSignal::from_axis(x));
+    let mut samples = vec![BinStats::default(); bins.count];
+    let mut fitted = vec![BinStats::default(); bins.count];
+    for ((&x, &y), &y_fit) in x.iter().zip(y).zip(y_fit) {
+        if let Some(i) = bins.index(x) {
+            samples[i].add(y);
+            fitted[i].add(y_fit);
+        }
+    }
+    samples.iter().zip(&fitted).enumerate()
+        .filter_map(|(i, (samples, fitted))| {
+            let (lo, hi) = bins.range(i);
+            let (mean, fit) = (samples.mean()?, fitted.mean()?);
+            (mean.abs() > f32::EPSILON).then(|| ((lo + hi) / 2.0, (fit / mean - 1.0) * 100.0))
+        })
+        .collect()
+}
+
+/// Plot width for the current terminal, from `COLUMNS` if it is set.
+pub fn terminal_width() -> usize {
+    env::var("COLUMNS").ok()
+        .and_then(|columns| columns.parse::<usize>().ok())
+        .map_or(DEFAULT_WIDTH, |columns| columns.saturating_sub(LABEL_WIDTH + 1).clamp(20, 200))
+}
+
+/// Plots the corrected samples `x, y` against the fitted values `y_fit`, then the change
+/// the fit makes to each bin, as braille text `width` characters wide.
+pub fn plot_fit(x: &[f32], y: &[f32], y_fit: &[f32], width: usize, color: bool) -> String {
+    let unit = MafSignal::from_axis(x).unit();
+    let x_range = range(x.iter().copied());
+    let mut text = format!("Corrected samples (dots) and fitted curve (line), g/s against MAF {}\n", unit);
+    let mut canvas = Canvas::new(width, HEIGHT, x_range, range(y.iter().chain(y_fit).copied()));
+    for (&x, &y) in x.iter().zip(y) {
+        canvas.point(x, y);
+    }
+    let mut curve: Vec<(f32, f32)> = x.iter().copied().zip(y_fit.iter().copied()).collect();
+    curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+    canvas.line(&curve);
+    text.push_str(&canvas.render(color));
+
+    // Bin centres can fall just outside the logged range
+    let profile: Vec<(f32, f32)> = change_profile(x, y, y_fit).into_iter()
+        .map(|(x, change)| (x.clamp(x_range.0, x_range.1), change))
+        .collect();
+    let _ = writeln!(text, "\nFit against the mean sample per bin, % against MAF {}", unit);
+    let (low, high) = range(profile.iter().map(|p| p.1));
+    let mut canvas = Canvas::new(width, PROFILE_HEIGHT, x_range, (low.min(0.0), high.max(0.0)));
+    // Dotted zero line
+    for i in (0..width * 2).step_by(2) {
+        canvas.point(x_range.0 + (x_range.1 - x_range.0) * i as f32 / (width * 2 - 1) as f32, 0.0);
+    }
+    canvas.line(&profile);
+    text.push_str(&canvas.render(color));
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canvas_and_profile() {
+        let mut canvas = Canvas::new(2, 1, (0.0, 3.0), (0.0, 1.0));
+        canvas.point(0.0, 1.0);
+        canvas.line(&[(2.0, 0.0), (3.0, 1.0)]);
+        let text = canvas.render(false);
+        // Top left dot in the first cell, a diagonal up the second
+        assert!(text.lines().next().unwrap().ends_with("│⠁⡜"), "{}", text);
+        assert!(canvas.render(true).contains("\x1b[31m⡜\x1b[0m"));
+
+        let x = [2.0, 2.01, 3.0];
+        let profile = change_profile(&x, &[10.0, 10.0, 20.0], &[11.0, 11.0, 20.0]);
+        assert_eq!(profile.len(), 2);
+        assert!((profile[0].1 - 10.0).abs() < 1e-4 && profile[1].1.abs() < 1e-4);
+
+        let text = plot_fit(&x, &[10.0, 10.0, 20.0], &[11.0, 11.0, 20.0], 40, false);
+        assert_eq!(text.lines().count(), 1 + HEIGHT + 2 + 2 + PROFILE_HEIGHT + 2);
+    }
+}
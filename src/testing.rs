@@ -0,0 +1,36 @@
+use crate::data::{LogData, LogField};
+
+/// A log row for a test: closed loop at a MAF signal with 20 g/s of airflow and no trims,
+/// unless `with` says otherwise.
+#[derive(Debug, Clone)]
+pub struct Row(Vec<(LogField, f32)>);
+
+/// Starts a row at MAF signal `x`.
+pub fn row(x: f32) -> Row {
+    Row(vec![(LogField::MAFV, x), (LogField::MASS, 20.0), (LogField::STFT, 0.0), (LogField::LTFT, 0.0)])
+}
+
+impl Row {
+    /// Sets `field` to `value`, replacing the value the row started with.
+    pub fn with(mut self, field: LogField, value: f32) -> Self {
+        match self.0.iter_mut().find(|(f, _)| *f == field) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((field, value)),
+        }
+        self
+    }
+}
+
+/// Builds a log from `rows`, with a channel for every field of the first row.
+pub fn log(rows: impl IntoIterator<Item = Row>) -> LogData {
+    let mut data = LogData::default();
+    for row in rows {
+        if data.is_empty() {
+            for &(field, _) in &row.0 {
+                data.add_field(field);
+            }
+        }
+        data.push_row(&row.0);
+    }
+    data
+}
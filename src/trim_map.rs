@@ -0,0 +1,292 @@
+use std::{
+    fmt,
+    fs::File,
+    io,
+    ops::Range,
+    path::Path,
+};
+use csv::Writer;
+use crate::{
+    bins::{BinStats, Bins, MAF_MAX_HZ, MAF_MAX_V},
+    data::MafSignal,
+    session::Sample,
+};
+
+/// Width of one MAF signal column of the map. Coarser than the table so cells fill up.
+const MAP_BIN_V: f32 = 0.25;
+const MAP_BIN_HZ: f32 = 500.0;
+/// Number of rows aimed for along the RPM or load axis.
+const TARGET_ROWS: usize = 16;
+/// Minimum samples a cell needs before its offset is trusted.
+pub const MIN_CELL_HITS: usize = 10;
+/// Offset (%) from the rest of its signal column at which a cell is reported.
+pub const HOTSPOT_PCT: f32 = 3.0;
+
+/// The second axis of a trim map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MapAxis {
+    #[default]
+    Rpm,
+    Load,
+}
+
+impl MapAxis {
+    /// Parses `rpm` or `load`.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "rpm" => Ok(MapAxis::Rpm),
+            "load" => Ok(MapAxis::Load),
+            _ => Err(format!("expected rpm or load, found '{}'", text)),
+        }
+    }
+
+    /// Returns the value of this axis for `sample`, NaN if its log has none.
+    pub fn value(self, sample: &Sample) -> f32 {
+        match self {
+            MapAxis::Rpm => sample.rpm,
+            MapAxis::Load => sample.load,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            MapAxis::Rpm => "RPM",
+            MapAxis::Load => "Load",
+        }
+    }
+}
+
+/// A cell whose mean trim departs from the rest of its signal column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hotspot {
+    pub column: usize,
+    pub row: usize,
+    pub hits: usize,
+    pub offset: f32,
+}
+
+/// Sample counts and mean corrected trims over MAF signal x RPM (or load).
+/// The means are weighted by `Sample::weight`, like the fit; the counts are not.
+///
+/// The MAF scaling applies one correction per signal column, whatever the engine speed,
+/// so a cell whose trim departs from the weighted mean of its column shows an error the
+/// scaling cannot remove, such as intake pulsation or a sensor housing problem at high RPM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrimMap {
+    pub signal: MafSignal,
+    pub axis: MapAxis,
+    pub columns: Bins,
+    pub rows: Bins,
+    /// Trim statistics indexed by signal column, then row.
+    pub cells: Vec<Vec<BinStats>>,
+}
+
+impl TrimMap {
+    /// Maps the trims of `samples` against `axis`.
+    /// Returns `None` if no sample carries a value for the axis.
+    pub fn new(samples: &[Sample], signal: MafSignal, axis: MapAxis) -> Option<Self> {
+        let columns = match signal {
+            MafSignal::Voltage => Bins::new(0.0, MAF_MAX_V, MAP_BIN_V),
+            MafSignal::Frequency => Bins::new(0.0, MAF_MAX_HZ, MAP_BIN_HZ),
+        };
+        let samples: Vec<(usize, f32, &Sample)> = samples.iter()
+            .filter(|s| s.trim.is_finite() && axis.value(s).is_finite())
+            .filter_map(|s| columns.index(s.x).map(|i| (i, axis.value(s), s)))
+            .collect();
+        let min = samples.iter().map(|s| s.1).reduce(f32::min)?;
+        let max = samples.iter().map(|s| s.1).reduce(f32::max)?;
+        let width = row_width(max - min);
+        let rows = Bins::new((min / width).floor() * width, max, width);
+
+        let mut cells = vec![vec![BinStats::default(); rows.count]; columns.count];
+        for (column, value, sample) in samples {
+            if let Some(row) = rows.index(value) {
+                cells[column][row].add_weighted(sample.trim, sample.weight);
+            }
+        }
+        Some(TrimMap { signal, axis, columns, rows, cells })
+    }
+
+    /// Returns the range of signal columns from the first to the last one with samples.
+    pub fn populated_columns(&self) -> Range<usize> {
+        let populated = |column: &Vec<BinStats>| column.iter().any(|cell| cell.hits > 0);
+        let first = self.cells.iter().position(populated).unwrap_or(0);
+        let last = self.cells.iter().rposition(populated).map_or(first, |last| last + 1);
+        first..last
+    }
+
+    /// Returns the mean trim of all samples in a signal column, so each row counts by its weight.
+    pub fn column_mean(&self, column: usize) -> Option<f32> {
+        let mut total = BinStats::default();
+        for cell in &self.cells[column] {
+            total.hits += cell.hits;
+            total.sum += cell.sum;
+            total.weight += cell.weight;
+        }
+        total.mean()
+    }
+
+    /// Returns how far the mean trim of a cell lies from the mean of its signal column.
+    pub fn offset(&self, column: usize, row: usize) -> Option<f32> {
+        Some(self.cells[column][row].mean()? - self.column_mean(column)?)
+    }
+
+    /// Returns the cells with at least `MIN_CELL_HITS` samples that are offset by `HOTSPOT_PCT`
+    /// or more from the rest of their column, largest offset first.
+    pub fn hotspots(&self) -> Vec<Hotspot> {
+        let mut hotspots: Vec<Hotspot> = self.cells.iter().enumerate()
+            .flat_map(|(column, cells)| cells.iter().enumerate().map(move |(row, cell)| (column, row, cell.hits)))
+            .filter(|&(_, _, hits)| hits >= MIN_CELL_HITS)
+            .filter_map(|(column, row, hits)| {
+                let offset = self.offset(column, row)?;
+                (offset.abs() >= HOTSPOT_PCT).then_some(Hotspot { column, row, hits, offset })
+            })
+            .collect();
+        hotspots.sort_by(|a, b| b.offset.abs().total_cmp(&a.offset.abs()));
+        hotspots
+    }
+
+    /// Writes every populated cell as one CSV record.
+    pub fn write_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = Writer::from_writer(File::create(path)?);
+        let unit = self.signal.unit();
+        let label = self.axis.label();
+        writer.write_record([
+            format!("MAF from ({})", unit),
+            format!("MAF to ({})", unit),
+            format!("{} from", label),
+            format!("{} to", label),
+            "Samples".to_string(),
+            "Mean trim (%)".to_string(),
+            "Offset (%)".to_string(),
+        ])?;
+        for (column, cells) in self.cells.iter().enumerate() {
+            for (row, cell) in cells.iter().enumerate() {
+                let Some(mean) = cell.mean() else { continue };
+                let (x_lo, x_hi) = self.columns.range(column);
+                let (y_lo, y_hi) = self.rows.range(row);
+                writer.write_record([
+                    x_lo.to_string(),
+                    x_hi.to_string(),
+                    y_lo.to_string(),
+                    y_hi.to_string(),
+                    cell.hits.to_string(),
+                    mean.to_string(),
+                    self.offset(column, row).unwrap_or(f32::NAN).to_string(),
+                ])?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Returns a round row width that splits `span` into about `TARGET_ROWS` rows.
+fn row_width(span: f32) -> f32 {
+    let raw = (span / TARGET_ROWS as f32).max(f32::EPSILON);
+    let magnitude = 10f32.powf(raw.log10().floor());
+    [1.0, 2.0, 5.0, 10.0].iter()
+        .map(|m| m * magnitude)
+        .find(|&width| width >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+impl fmt::Display for TrimMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let populated = self.cells.iter().flatten().filter(|cell| cell.hits > 0).count();
+        writeln!(
+            f,
+            "Trim map by MAF {} and {}: {} populated cells of {} x {}",
+            self.signal.unit(), self.axis.label(), populated, self.populated_columns().len(), self.rows.count
+        )?;
+        let hotspots = self.hotspots();
+        if hotspots.is_empty() {
+            return writeln!(
+                f,
+                "No cell departs from its MAF column by {}% or more; the trims follow the MAF signal alone.",
+                HOTSPOT_PCT
+            );
+        }
+        writeln!(f, "Cells whose trim departs from the rest of their MAF column (pulsation or sensor housing, not scaling):")?;
+        for hotspot in hotspots {
+            let (x_lo, x_hi) = self.columns.range(hotspot.column);
+            let (y_lo, y_hi) = self.rows.range(hotspot.row);
+            writeln!(
+                f,
+                "  {:>5.2}-{:<5.2} {}  {:>6}-{:<6} {}  {:>+6.2}%  ({} samples)",
+                x_lo, x_hi, self.signal.unit(), y_lo, y_hi, self.axis.label(), hotspot.offset, hotspot.hits
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Flat trims, except 6% rich above 5000 RPM between 2.00 and 2.25 V.
+    fn samples() -> Vec<Sample> {
+        (0..800)
+            .map(|i| {
+                let x = 1.0 + (i % 20) as f32 * 0.1;
+                let rpm = 1000.0 + (i / 20) as f32 * 125.0;
+                let trim = if (2.0..2.25).contains(&x) && rpm >= 5000.0 { 6.0 } else { 0.0 };
+                Sample { trim, rpm, ..Sample::point(x, 10.0) }
+            })
+            .collect()
+    }
+
+    fn map() -> TrimMap {
+        TrimMap::new(&samples(), MafSignal::Voltage, MapAxis::Rpm).unwrap()
+    }
+
+    #[test]
+    fn test_no_map_without_axis() {
+        assert!(TrimMap::new(&samples(), MafSignal::Voltage, MapAxis::Load).is_none());
+    }
+
+    #[test]
+    fn test_cells() {
+        let map = map();
+        assert_eq!(map.populated_columns(), 4..12);
+        assert_eq!(map.rows.width, 500.0);
+        assert_eq!(map.rows.range(0), (1000.0, 1500.0));
+        let column = map.columns.index(2.1).unwrap();
+        assert_eq!(map.cells[column].iter().map(|cell| cell.hits).sum::<usize>(), 120);
+    }
+
+    #[test]
+    fn test_weighted_cells() {
+        let samples = [(0.0, 3.0), (4.0, 1.0)]
+            .map(|(trim, weight)| Sample { trim, weight, rpm: 2000.0, ..Sample::point(2.1, 10.0) });
+        let map = TrimMap::new(&samples, MafSignal::Voltage, MapAxis::Rpm).unwrap();
+        let cell = map.cells[map.columns.index(2.1).unwrap()][0];
+        assert_eq!(cell.hits, 2);
+        assert_eq!(cell.mean(), Some(1.0));
+    }
+
+    #[test]
+    fn test_hotspots() {
+        let map = map();
+        let hotspots = map.hotspots();
+        let mut rows: Vec<f32> = hotspots.iter().map(|h| map.rows.range(h.row).0).collect();
+        rows.sort_by(f32::total_cmp);
+        assert_eq!(rows, vec![5000.0, 5500.0]);
+        let column = map.columns.index(2.1).unwrap();
+        assert!(hotspots.iter().all(|h| h.column == column && (h.offset - 4.8).abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_display_and_heatmap() {
+        let map = map();
+        assert!(map.to_string().contains("2.00-2.25"));
+        let svg = crate::plot::render_heatmap(&map);
+        assert_eq!(svg.matches(r#"stroke-width="2""#).count(), 2);
+    }
+
+    #[test]
+    fn test_parse_axis() {
+        assert_eq!(MapAxis::parse("LOAD"), Ok(MapAxis::Load));
+        assert!(MapAxis::parse("tps").is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+use csv::{ByteRecord, ReaderBuilder};
+use crate::{
+    correction::CorrectionConfig,
+    live::{LiveDisplay, RollingBins},
+    log_reader::{footer_info, HeaderMap},
+};
+
+/// How often the watched file is checked for new rows.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Bytes at the start of the file compared between polls, to notice it being rewritten in place.
+const FINGERPRINT_LEN: u64 = 256;
+
+/// Follows a log file that another program is still writing, like `tail -f`.
+///
+/// Complete lines are parsed as they appear and added to running per-bin trims and airflow.
+/// With a `d/dt` rule, each row waits for the next one so its rate of change is known.
+/// If the file is truncated, rewritten or replaced (log rotation) it is read again from the start
+/// and the bins start over; the session only counts as a new one if the header changed.
+pub struct Watcher {
+    path: PathBuf,
+    config: CorrectionConfig,
+    file: Option<File>,
+    id: Option<u64>,
+    /// The first bytes of the file as last read, see `FINGERPRINT_LEN`.
+    fingerprint: Vec<u8>,
+    offset: u64,
+    /// Bytes after the last complete line.
+    pending: Vec<u8>,
+    /// Raw header line and its mapping, once the header has been read.
+    header: Option<(Vec<u8>, HeaderMap)>,
+    /// Whether the header of the current file has been read.
+    header_read: bool,
+    /// The last rows parsed, kept for the rate of change of the rows either side of a chunk.
+    /// The last of them has not been added yet.
+    held: Vec<Vec<f32>>,
+    pub rolling: Option<RollingBins>,
+    pub sessions: usize,
+    pub skipped: usize,
+}
+
+impl Watcher {
+    /// Watches `path`, correcting rows with `config`. Nothing is read until `poll`.
+    pub fn new<P: AsRef<Path>>(path: P, config: CorrectionConfig) -> Self {
+        Watcher {
+            path: path.as_ref().to_path_buf(),
+            config,
+            file: None,
+            id: None,
+            fingerprint: Vec::new(),
+            offset: 0,
+            pending: Vec::new(),
+            header: None,
+            header_read: false,
+            held: Vec::new(),
+            rolling: None,
+            sessions: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Reads whatever was appended since the last poll. Returns `true` if any rows were added
+    /// or a new session started. A missing file is waited for rather than an error.
+    pub fn poll(&mut self) -> io::Result<bool> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let id = file_id(&metadata);
+        let rewritten = match self.file.as_mut() {
+            Some(file) if id == self.id && metadata.len() >= self.offset => {
+                let fingerprint = fingerprint(file)?;
+                let common = fingerprint.len().min(self.fingerprint.len());
+                fingerprint[..common] != self.fingerprint[..common]
+            }
+            _ => true,
+        };
+        if rewritten {
+            // New, rotated, truncated or rewritten file: read it again from the start
+            self.file = Some(File::open(&self.path)?);
+            self.id = id;
+            self.offset = 0;
+            self.pending.clear();
+            self.header_read = false;
+            self.held.clear();
+            self.skipped = 0;
+            if let Some(rolling) = self.rolling.as_mut() {
+                *rolling = RollingBins::new(rolling.signal);
+            }
+        }
+        let Some(file) = self.file.as_mut() else {
+            return Ok(false);
+        };
+        self.fingerprint = fingerprint(file)?;
+
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file.read_to_end(&mut self.pending)?;
+        self.offset += read as u64;
+        let Some(end) = self.pending.iter().rposition(|&byte| byte == b'\n') else {
+            return Ok(false);
+        };
+        let lines: Vec<u8> = self.pending.drain(..=end).collect();
+        self.process(&lines)
+    }
+
+    /// Parses complete lines, starting a session at a header line.
+    fn process(&mut self, lines: &[u8]) -> io::Result<bool> {
+        let mut changed = false;
+        let mut lines = lines;
+        if !self.header_read {
+            let end = lines.iter().position(|&byte| byte == b'\n').unwrap_or(lines.len());
+            let header_line = lines[..end].to_vec();
+            lines = &lines[(end + 1).min(lines.len())..];
+            self.header_read = true;
+            if self.header.as_ref().is_none_or(|(previous, _)| *previous != header_line) {
+                let mut record = ByteRecord::new();
+                ReaderBuilder::new().has_headers(false).from_reader(&header_line[..]).read_byte_record(&mut record)?;
+                let name = self.path.display().to_string();
+                let map = HeaderMap::new(&record, &name, &self.config.blacklist)?;
+                self.rolling = Some(RollingBins::new(map.signal));
+                self.header = Some((header_line, map));
+                self.sessions += 1;
+                changed = true;
+            }
+        }
+
+        let (Some((_, map)), Some(rolling)) = (self.header.as_ref(), self.rolling.as_mut()) else {
+            return Ok(changed);
+        };
+        let mut reader = ReaderBuilder::new().has_headers(false).flexible(true).from_reader(lines);
+        let mut record = ByteRecord::new();
+        let mut rows = std::mem::take(&mut self.held);
+        let held = rows.len();
+        let mut row = Vec::with_capacity(map.columns.len());
+        while reader.read_byte_record(&mut record)? {
+            if footer_info(&record).is_some() {
+                continue;
+            }
+            match map.parse_row(&record, &mut row) {
+                Ok(()) => rows.push(row.clone()),
+                Err(_) => self.skipped += 1,
+            }
+        }
+        if rows.len() == held {
+            self.held = rows;
+            return Ok(changed);
+        }
+
+        // The held rows give the first new row its previous neighbour, and with a `d/dt` rule
+        // the last row waits for its next one
+        let lookahead = usize::from(self.config.rules.uses_derivatives());
+        let mut data = map.empty_log();
+        for row in &rows {
+            data.push_values(row);
+        }
+        let (mut keep, _) = self.config.rules.keep(&data);
+        let ready = held.saturating_sub(lookahead)..rows.len() - lookahead;
+        for (i, keep) in keep.iter_mut().enumerate() {
+            *keep &= ready.contains(&i);
+        }
+        data.retain_rows(&keep);
+        if lookahead > 0 {
+            self.held = rows.split_off(rows.len().saturating_sub(2));
+        }
+
+        self.config.blacklist.drop_times(&self.path, &mut data)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", self.path.display(), e)))?;
+        if !data.is_empty() {
+            rolling.add_rows(&data, &self.config)?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
+
+/// Reads the first `FINGERPRINT_LEN` bytes of `file`.
+fn fingerprint(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.take(FINGERPRINT_LEN).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Identifies the file behind a path, so a replaced file is told apart from a grown one.
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Watches `path` until interrupted, redrawing the running per-bin trims as rows arrive.
+/// The rules and blacklist apply as they do to a complete log; transport delay alignment,
+/// resampling and weighting need the whole log and are not supported.
+pub fn watch(path: &Path, config: CorrectionConfig) -> io::Result<()> {
+    let mut watcher = Watcher::new(path, config);
+    let mut display = LiveDisplay::default();
+    println!("Watching {}", path.display());
+    loop {
+        if watcher.poll()? {
+            if let Some(rolling) = &watcher.rolling {
+                display.refresh(rolling, false)?;
+                println!("Session {}, {} malformed rows skipped", watcher.sessions, watcher.skipped);
+            }
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::{
+        blacklist::Blacklist,
+        rules::RuleSet,
+    };
+
+    #[test]
+    fn test_follow_truncate_and_new_header() {
+        let path = std::env::temp_dir().join(format!("maf_cal_watch_{}.csv", std::process::id()));
+        let header = "Time,MAF Voltage,Mass Airflow,Short Term FT,Long Term FT\n";
+        fs::write(&path, format!("{}0.0,2.1,20,1,0\n0.1,2.1,20,", header)).unwrap();
+        let mut watcher = Watcher::new(&path, CorrectionConfig::default());
+        assert!(watcher.poll().unwrap());
+        assert_eq!(watcher.sessions, 1);
+        assert_eq!(watcher.rolling.as_ref().unwrap().rows, 1);
+
+        // The partial line completes, then another row arrives
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"3,0\n0.2,2.2,20,5,0\n").unwrap();
+        assert!(watcher.poll().unwrap());
+        assert!(!watcher.poll().unwrap());
+        let rolling = watcher.rolling.as_ref().unwrap();
+        assert_eq!(rolling.rows, 3);
+        assert_eq!(rolling.trims[8].mean(), Some(3.0));
+
+        // Truncated and restarted with the same header: the session continues with fresh bins
+        fs::write(&path, format!("{}0.0,2.1,20,1,0\n", header)).unwrap();
+        assert!(watcher.poll().unwrap());
+        assert_eq!((watcher.sessions, watcher.rolling.as_ref().unwrap().rows), (1, 1));
+
+        // Rotated to a file with a different header: a new session starts
+        let rotated = path.with_extension("new");
+        fs::write(&rotated, "Time,Intake Temp,MAF Voltage,Mass Airflow,Short Term FT,Long Term FT\n0.0,70,2.1,20,1,0\n").unwrap();
+        fs::rename(&rotated, &path).unwrap();
+        assert!(watcher.poll().unwrap());
+        assert_eq!((watcher.sessions, watcher.rolling.as_ref().unwrap().rows), (2, 1));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_in_place_starts_over() {
+        let path = std::env::temp_dir().join(format!("maf_cal_watch_rewrite_{}.csv", std::process::id()));
+        let header = "Time,MAF Voltage,Mass Airflow,Short Term FT,Long Term FT\n";
+        fs::write(&path, format!("{}0.0,2.1,20,1,0\nbad\n", header)).unwrap();
+        let mut watcher = Watcher::new(&path, CorrectionConfig::default());
+        let first = watcher.poll().map(|_| watcher.skipped);
+
+        // Rewritten between polls to a larger file, in the same inode
+        fs::write(&path, format!("{}0.0,2.2,20,5,0\n0.1,2.2,20,5,0\n", header)).unwrap();
+        let second = watcher.poll();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(first.unwrap(), 1);
+        assert!(second.unwrap());
+        let rolling = watcher.rolling.as_ref().unwrap();
+        assert_eq!((watcher.sessions, watcher.skipped, rolling.rows), (1, 0, 2));
+        assert_eq!(rolling.trims[8].mean(), Some(5.0));
+    }
+
+    #[test]
+    fn test_rate_rules_span_chunks() {
+        let path = std::env::temp_dir().join(format!("maf_cal_watch_rate_{}.csv", std::process::id()));
+        let header = "Time,MAF Voltage,Mass Airflow,Short Term FT,Long Term FT\n";
+        fs::write(&path, format!("{}0.0,2.0,20,1,0\n0.1,2.0,20,1,0\n0.2,2.0,20,9,0\n", header)).unwrap();
+        let rules = RuleSet::parse("\"d/dt MAF Voltage\" > 4 => drop").unwrap();
+        let mut watcher = Watcher::new(&path, CorrectionConfig { rules, ..CorrectionConfig::default() });
+        let first = watcher.poll().map(|_| watcher.rolling.as_ref().unwrap().rows);
+
+        // The jump in the next chunk makes the row before it change at 5 V/s
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"0.3,3.0,20,1,0\nAP Info:[AP3-MAZ-002 v1.7.3.1-16781][USDM 2011-2013 MAZDASPEED3.ptm]\n").unwrap();
+        let second = watcher.poll();
+        fs::remove_file(&path).unwrap();
+        // The last row waits for the next before its rate of change is known
+        assert_eq!(first.unwrap(), 2);
+        // The only row ready, the one before the jump, is dropped
+        assert!(!second.unwrap());
+        let rolling = watcher.rolling.as_ref().unwrap();
+        assert_eq!((rolling.rows, watcher.skipped), (2, 0));
+        assert_eq!(rolling.trims[8].mean(), Some(1.0));
+    }
+
+    #[test]
+    fn test_blacklisted_times_are_dropped() {
+        let path = std::env::temp_dir().join(format!("maf_cal_watch_blacklist_{}.csv", std::process::id()));
+        let blacklist = Blacklist::parse(&format!("time {} 0.1 0.2\n", path.file_name().unwrap().to_string_lossy())).unwrap();
+        fs::write(&path, "Time,MAF Voltage,Mass Airflow,Short Term FT,Long Term FT\n0.0,2.1,20,1,0\n0.1,2.1,20,9,0\n0.3,2.1,20,3,0\n").unwrap();
+        let mut watcher = Watcher::new(&path, CorrectionConfig { blacklist, ..CorrectionConfig::default() });
+        let polled = watcher.poll();
+        fs::remove_file(&path).unwrap();
+        assert!(polled.unwrap());
+        let rolling = watcher.rolling.as_ref().unwrap();
+        assert_eq!(rolling.rows, 2);
+        assert_eq!(rolling.trims[8].mean(), Some(2.0));
+    }
+}